@@ -0,0 +1,209 @@
+use errors::*;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use serde_json;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+use telegram::Telegram;
+use utils;
+use utils::BoxFuture;
+
+const INDEX_FILE: &str = "index.json";
+
+/*
+ * What we know about one archived file
+ * Dates are unix timestamps of the messages.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub kind: String,
+    pub file_id: String,
+    // Relative to the archive directory
+    pub file_name: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub chats: Vec<i64>,
+    pub count: i64,
+    pub downloaded: bool
+}
+
+/*
+ * Local archive of the media sent by Rikka
+ * Every file is downloaded once into `<dir>/<kind>/`,
+ * deduplicated by its file_unique_id, with an index
+ * of all the files in `<dir>/index.json`.
+ *
+ * The index is shared with the pending downloads,
+ * so that they can mark the files as downloaded.
+ * Single-threaded, just like State.
+ */
+pub struct Archive {
+    pool: Rc<CpuPool>,
+    dir: String,
+    index: Rc<RefCell<HashMap<String, ArchiveEntry>>>,
+    // Downloads in progress, by file_unique_id
+    pending: Rc<RefCell<HashSet<String>>>,
+    saving: Rc<RefCell<Saving>>
+}
+
+/*
+ * At most one write of the index at a time
+ * Whatever asks for a save meanwhile is batched
+ * into a single write once it is done.
+ */
+#[derive(Default)]
+struct Saving {
+    in_flight: bool,
+    again: bool
+}
+
+impl Archive {
+    pub fn new(pool: Rc<CpuPool>, dir: String) -> Archive {
+        Archive {
+            pool,
+            dir,
+            index: Rc::new(RefCell::new(HashMap::new())),
+            pending: Rc::new(RefCell::new(HashSet::new())),
+            saving: Rc::new(RefCell::new(Saving::default()))
+        }
+    }
+
+    /*
+     * Create the directories and load the index
+     * Takes ownership of self, like State::load.
+     * Fails rather than starting over an index that cannot be read.
+     */
+    pub fn load<'a>(self, kinds: &[&str]) -> BoxFuture<'a, Archive> {
+        let dirs: Vec<String> = kinds.iter().map(|kind| format!("{}/{}", self.dir, kind)).collect();
+        let index_file = self.index_file();
+        Box::new(self.pool.spawn_fn(move || -> Result<_> {
+                for dir in dirs {
+                    fs::create_dir_all(dir)?;
+                }
+                match fs::read_to_string(&index_file) {
+                    Ok(s) => serde_json::from_str(&s)
+                        .chain_err(|| format!("Failed to decode {}. Refusing to start over it.", index_file)),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        info!("{} does not exist. Starting with an empty archive.", index_file);
+                        Ok(HashMap::new())
+                    },
+                    Err(e) => Err(e).chain_err(|| format!("Failed to read {}", index_file))
+                }
+            })
+            .map(move |index| {
+                *self.index.borrow_mut() = index;
+                self
+            }))
+    }
+
+    /*
+     * Record one sighting of a file in `chat_id` at `date`
+     * and download it if it is not in the archive yet.
+     */
+    pub fn record<'a>(&self, tg: &Telegram, kind: &str, file_id: &str, unique_id: &str, extension: &str, chat_id: i64, date: i64) -> BoxFuture<'a, ()> {
+        let file_name = {
+            let mut index = self.index.borrow_mut();
+            let entry = index.entry(unique_id.to_string()).or_insert_with(|| ArchiveEntry {
+                kind: kind.to_string(),
+                file_id: file_id.to_string(),
+                file_name: format!("{}/{}.{}", kind, unique_id, extension),
+                first_seen: date,
+                last_seen: date,
+                chats: vec![],
+                count: 0,
+                downloaded: false
+            });
+            // The latest file_id is the most likely to still work
+            entry.file_id = file_id.to_string();
+            entry.first_seen = cmp::min(entry.first_seen, date);
+            entry.last_seen = cmp::max(entry.last_seen, date);
+            entry.count += 1;
+            if !entry.chats.contains(&chat_id) {
+                entry.chats.push(chat_id);
+            }
+            if entry.downloaded || self.pending.borrow().contains(unique_id) {
+                None
+            } else {
+                Some(entry.file_name.clone())
+            }
+        };
+
+        let save = self.save();
+        let file_name = match file_name {
+            Some(file_name) => file_name,
+            None => return save
+        };
+
+        info!("Archiving {} {} as {}", kind, unique_id, file_name);
+        self.pending.borrow_mut().insert(unique_id.to_string());
+        let unique_id = unique_id.to_string();
+        let index = self.index.clone();
+        let pending = self.pending.clone();
+        let pool = self.pool.clone();
+        let index_file = self.index_file();
+        let saving = self.saving.clone();
+        let download = tg.download_file(file_id, &format!("{}/{}", self.dir, file_name))
+            .then(move |res| {
+                pending.borrow_mut().remove(&unique_id);
+                match res {
+                    Ok(size) => {
+                        info!("Archived {} ({} bytes)", file_name, size);
+                        if let Some(entry) = index.borrow_mut().get_mut(&unique_id) {
+                            entry.downloaded = true;
+                        }
+                        save_index(pool, index_file, index, saving)
+                    },
+                    Err(e) => {
+                        // Will be retried the next time it is seen
+                        warn!("Failed to archive {}: {:?}", file_name, e);
+                        utils::return_empty()
+                    }
+                }
+            });
+        Box::new(save.join(download).map(|_| ()))
+    }
+
+    pub fn save<'a>(&self) -> BoxFuture<'a, ()> {
+        save_index(self.pool.clone(), self.index_file(), self.index.clone(), self.saving.clone())
+    }
+
+    fn index_file(&self) -> String {
+        format!("{}/{}", self.dir, INDEX_FILE)
+    }
+}
+
+/*
+ * Write the index, or leave it to the write in progress
+ * to write it once more when done
+ */
+fn save_index<'a>(pool: Rc<CpuPool>, index_file: String, index: Rc<RefCell<HashMap<String, ArchiveEntry>>>,
+                  saving: Rc<RefCell<Saving>>) -> BoxFuture<'a, ()> {
+    {
+        let mut saving = saving.borrow_mut();
+        if saving.in_flight {
+            saving.again = true;
+            return utils::return_empty();
+        }
+        saving.in_flight = true;
+    }
+    let text = serde_json::to_string_pretty(&*index.borrow()).unwrap();
+    Box::new(utils::write_file_str_async(&pool, index_file.clone(), text)
+        .then(move |res| {
+            let again = {
+                let mut saving = saving.borrow_mut();
+                saving.in_flight = false;
+                mem::replace(&mut saving.again, false)
+            };
+            let next = if again {
+                save_index(pool, index_file, index, saving)
+            } else {
+                utils::return_empty()
+            };
+            next.then(move |next_res| res.and(next_res))
+        }))
+}
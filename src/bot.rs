@@ -1,12 +1,21 @@
-use futures::Future;
+use archive::Archive;
+use cleanup;
+use futures::{future, Future};
+use futures::future::Loop;
 use futures_cpupool::CpuPool;
+use markup::Text;
+use middleware::{Authorization, Filter, Logger, Metrics, RateLimit};
 use rand;
 use rand::Rng;
+use render;
 use state::State;
-use std::cmp::Ordering;
+use storage;
+use stats::{self, Scope, Window, MEDIA_ANIMATION, MEDIA_KINDS, MEDIA_PHOTO, MEDIA_STICKER, MEDIA_VOICE};
+use std::cmp;
 use std::collections::HashMap;
 use std::rc::Rc;
-use telegram::{Message, Result, Telegram, Update, User};
+use std::time::Duration;
+use telegram::{Api, CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, InlineQuery, InlineQueryResult, InputFile, Message, Result, Telegram, Update, User};
 use time;
 use utils::{self, BoxFuture, Config, FutureChainErr};
 
@@ -16,13 +25,67 @@ Hello, I am the bot that tries to become Rikka! (or at least one day ^_^)
 /hello - Say hello to Rikka!
 /help - Print this message.
 /ping - Is Rikka here now?
-/rikka - Rikka Rikka Ri!
+/stats - Rikka's favourite stickers (or /stats global, /stats 7d, /stats chart)
+/trending - Stickers Rikka has been using a lot lately
+/when - When is Rikka around?
+/rikka - Rikka Rikka Ri! (or /rikka gif, /rikka photo, /rikka voice)
+/autoreply - Reply with stickers when mentioned (on, off, or a probability)
+/autodelete - Delete my replies to commands after a while (seconds, or off)
 "#;
 
+// Entries on each page of `/stats`
+const STATS_PAGE_SIZE: usize = 10;
+const STATS_BUTTONS_PER_ROW: usize = 5;
+const STATS_WINDOWS: [Window; 3] = [Window::Days(7), Window::Days(30), Window::All];
+const STATS_CHARTS: [&str; 3] = ["top", "usage", "users"];
+// Days shown by the usage chart if no window is given
+const DEFAULT_CHART_DAYS: i64 = 30;
+
+// From none to the busiest hour in `/when`
+const HEATMAP_SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+// `/trending` compares the last week to the four weeks before
+const TRENDING_RECENT_DAYS: i64 = 7;
+const TRENDING_BASELINE_DAYS: i64 = 28;
+
+// Media from Rikka that are kept in the archive, if enabled
+const ARCHIVED_KINDS: [&str; 3] = [MEDIA_STICKER, MEDIA_ANIMATION, MEDIA_PHOTO];
+
+// Telegram takes at most 50 results per inline answer
+const INLINE_PAGE_SIZE: usize = 50;
+// Seconds. Short, since the results are personalized.
+const INLINE_CACHE_TIME: i64 = 30;
+
+// Chance of an automatic reply unless set by `/autoreply <probability>`
+const DEFAULT_AUTOREPLY_PROB: f64 = 0.5;
+
+// The update_id of the first update not handled yet, and when it was
+const UPDATE_OFFSET_KEY: &str = "update_offset";
+const UPDATE_OFFSET_TIME_KEY: &str = "update_offset_time";
+// Telegram keeps updates for a day, and after a week without
+// any, it may even start over from a lower update_id.
+const UPDATE_OFFSET_MAX_AGE: i64 = 24 * 60 * 60;
+
+// Log the number of updates of each kind every this many updates
+const METRICS_INTERVAL: u64 = 1000;
+
+// How often to check whether the unsaved changes are too old
+const AUTOSAVE_CHECK_SECS: u64 = 1;
+
+// How often to forget the statistics too old to matter
+const PRUNE_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+// Telegram only allows deleting messages of the last 48 hours
+const MAX_AUTODELETE_DELAY: i64 = 48 * 60 * 60;
+
 macro_rules! cmd_fn_type {
     () => (fn (&mut Telegram, &State, &Config, &str, &Message, Vec<&str>) -> BoxFuture<'a, ()>)
 }
 
+// Handlers of button presses, with the message the buttons are under
+type CallbackFn = fn (&mut Telegram, &State, &Config, &CallbackQuery, &Message, Vec<&str>) -> BoxFuture<'static, ()>;
+
 fn command_map<'a>() -> HashMap<String, cmd_fn_type!()> {
     string_hashmap! {
         cmd_fn_type!();
@@ -31,7 +94,42 @@ fn command_map<'a>() -> HashMap<String, cmd_fn_type!()> {
         "print_cmds" => cmd_print_cmds,
         "ping" => cmd_ping,
         "stats" => cmd_stats,
-        "rikka" => cmd_rikka
+        "dump" => cmd_dump,
+        "trending" => cmd_trending,
+        "when" => cmd_when,
+        "rikka" => cmd_rikka,
+        "autoreply" => cmd_autoreply,
+        "autodelete" => cmd_autodelete
+    }
+}
+
+/*
+ * Routes of the inline keyboard buttons
+ * Callback data is a list of arguments separated by `:`
+ * with the first one naming the route, e.g. `stats:g:7d:1`.
+ * Anything the handler needs must be in the arguments,
+ * and the whole data must fit in 64 bytes.
+ *
+ * Whatever sends buttons registers the routes for them
+ * at startup, see e.g. `stats_routes`.
+ */
+struct Callbacks {
+    routes: HashMap<&'static str, CallbackFn>
+}
+
+impl Callbacks {
+    fn new() -> Callbacks {
+        Callbacks { routes: HashMap::new() }
+    }
+
+    fn route(&mut self, name: &'static str, handler: CallbackFn) {
+        if self.routes.insert(name, handler).is_some() {
+            panic!("Callback route {} registered twice", name);
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<CallbackFn> {
+        self.routes.get(name).cloned()
     }
 }
 
@@ -41,39 +139,177 @@ fn command_map<'a>() -> HashMap<String, cmd_fn_type!()> {
  * Passes the Telegram object reference back.
  */
 pub fn bot_main<'a>(tg: &'a mut Telegram, config: Config, pool: Rc<CpuPool>) -> BoxFuture<'a, &'a mut Telegram> {
-    tg.get("getMe", params!{})
+    Box::new(tg.get("getMe", params!{})
         .and_then(|result| {
             assert_result!(Result::User(result), result, Err("I must exist.".into()));
             Ok(result)
         })
-        .and_then(move |result| {
+        .chain_err(|| "Failed to fetch bot username.")
+        .and_then(move |result| -> BoxFuture<'a, (Config, State, Option<Archive>, String)> {
             let name = result.username.expect("I must have a username.");
             info!("I am @{}", name);
-            let state = State::new(pool.clone(), config.state_file.clone());
-            state.load()
-                .map(move |state| (config, state, name))
+            let policy = config.save_policy.clone().unwrap_or_default();
+            let backend = match storage::from_config(&config) {
+                Ok(backend) => backend,
+                Err(e) => return Box::new(future::err(e.chain_err(|| "Failed to open the storage.")))
+            };
+            let state = State::new(pool.clone(), config.state_file.clone(), backend, policy);
+            let archive: BoxFuture<'a, Option<Archive>> = match config.archive_dir {
+                Some(ref dir) => Box::new(Archive::new(pool.clone(), dir.clone())
+                    .load(&ARCHIVED_KINDS)
+                    .map(Some)),
+                None => Box::new(future::ok(None))
+            };
+            Box::new(state.load()
+                .chain_err(|| "Failed to load the state.")
+                .join(archive)
+                .map(move |(state, archive)| (config, state, archive, name)))
         })
-        .and_then(move |(config, state, name)| {
-            tg.subscribe(move |_, tg, update| bot_on_update(tg, &state, &config, &name, update));
-            Ok(tg)
-        })
-        .chain_err(|| "Failed to fetch bot username.")
+        .and_then(move |(config, state, archive, name)| {
+            // Carry on from the last update whose effects were saved
+            // unless that was too long ago for the offset to be any good
+            let saved_at = state.get::<i64>(UPDATE_OFFSET_TIME_KEY).unwrap_or(0);
+            match state.get::<i64>(UPDATE_OFFSET_KEY) {
+                Some(offset) if time::get_time().sec - saved_at < UPDATE_OFFSET_MAX_AGE => {
+                    info!("Resuming from update {}", offset);
+                    tg.set_update_offset(offset);
+                },
+                Some(_) => info!("The saved update offset is too old. Ignoring it."),
+                None => ()
+            }
+            cleanup::restore_pending(&tg.api(), &state);
+            // Whatever is not saved yet would be lost otherwise
+            if state.policy().on_shutdown.unwrap_or(true) {
+                let state_ = state.clone();
+                tg.on_shutdown(move || state_.save());
+            }
+            if state.policy().max_dirty_secs.is_some() {
+                spawn_autosave(&tg.api(), &state);
+            }
+            spawn_prune(&tg.api(), &state);
+            add_middlewares(tg, &config);
+            let drop_pending = config.drop_pending_updates.unwrap_or(false);
+            let mut callbacks = Callbacks::new();
+            stats_routes(&mut callbacks);
+            rikka_routes(&mut callbacks);
+            tg.subscribe(move |_, tg, update| bot_on_update(tg, &state, archive.as_ref(), &callbacks, &config, &name, update));
+            if !drop_pending {
+                return Box::new(future::ok(tg)) as BoxFuture<'a, &'a mut Telegram>;
+            }
+            // Whatever came in while we were down is left unhandled
+            info!("Dropping pending updates");
+            Box::new(tg.api().delete_webhook(true).map(move |_| tg))
+        }))
 }
 
-fn bot_on_update<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, update: &Update) -> BoxFuture<'a, ()> {
-    info!("New update received: {:?}", update);
-    if let Some(ref msg) = update.message {
+/*
+ * Save the state once its unsaved changes are too old
+ * even if no more updates come in to trigger it
+ */
+fn spawn_autosave(api: &Api, state: &State) {
+    let api_ = api.clone();
+    let state = state.clone();
+    api.spawn_background(future::loop_fn((), move |_| {
+        let state = state.clone();
+        api_.delay(Duration::from_secs(AUTOSAVE_CHECK_SECS))
+            .and_then(move |_| state.save_if_needed().then(|res| {
+                // Try again next time
+                if let Err(e) = res {
+                    error!("Failed to save state: {:?}", e);
+                }
+                Ok(Loop::Continue(()))
+            }))
+    }));
+}
+
+/*
+ * Prune the statistics now and every PRUNE_INTERVAL_SECS
+ * See stats::prune.
+ */
+fn spawn_prune(api: &Api, state: &State) {
+    let api_ = api.clone();
+    let state = state.clone();
+    api.spawn_background(future::loop_fn((), move |_| {
+        let pruned = stats::prune(&state, time::get_time().sec);
+        if pruned > 0 {
+            info!("Pruned {} stale keys", pruned);
+        }
+        let api_ = api_.clone();
+        state.save_if_needed()
+            .then(|res| {
+                // Try again next time
+                if let Err(e) = res {
+                    error!("Failed to save state: {:?}", e);
+                }
+                Ok(())
+            })
+            .and_then(move |_| api_.delay(Duration::from_secs(PRUNE_INTERVAL_SECS)))
+            .map(|_| Loop::Continue(()))
+    }));
+}
+
+/*
+ * Everything an update goes through before bot_on_update
+ * Logged first, so that even the dropped ones show up.
+ */
+fn add_middlewares(tg: &mut Telegram, config: &Config) {
+    tg.add_middleware(Logger);
+    if config.blocked_users.is_some() || config.allowed_chats.is_some() {
+        tg.add_middleware(Authorization::new(
+            config.blocked_users.clone().unwrap_or_default(),
+            config.allowed_chats.clone().unwrap_or_default()));
+    }
+    tg.add_middleware(Metrics::new(METRICS_INTERVAL));
+    if let Some(limit) = config.commands_per_minute {
+        tg.add_middleware_filtered(Filter::new().any_command(), RateLimit::new(limit, 60));
+    }
+}
+
+fn bot_on_update<'a>(tg: &mut Telegram, state: &State, archive: Option<&Archive>, callbacks: &Callbacks, config: &Config, username: &str, update: &Update) -> BoxFuture<'a, ()> {
+    // Saved together with whatever this update records
+    state.put_quietly(UPDATE_OFFSET_KEY, &(update.update_id + 1));
+    state.put_quietly(UPDATE_OFFSET_TIME_KEY, &time::get_time().sec);
+
+    let handled = if let Some(ref msg) = update.message {
         // A new Message
-        bot_on_message(tg, state, config, username, msg)
+        bot_on_message(tg, state, archive, config, username, update.update_id, msg)
+    } else if let Some(ref query) = update.callback_query {
+        // A button under one of our messages was pressed
+        bot_on_callback(tg, state, callbacks, config, query)
+    } else if let Some(ref query) = update.inline_query {
+        // Someone typed `@us ...` in a chat
+        bot_on_inline_query(tg, state, query)
+    } else if let Some(ref result) = update.chosen_inline_result {
+        // One of our inline results was sent
+        info!("Inline result {} chosen by {}", result.result_id, result.from.id);
+        stats::record_inline_choice(state, result.from.id, &result.result_id);
+        state.save_if_needed()
     } else {
         // Unrecognized update. Just ignore it.
         warn!("Unrecognized update received. Ignoring.");
         utils::return_empty()
-    }
+    };
+
+    // All of its changes to the journal at once
+    let state = state.clone();
+    Box::new(handled.then(move |res| state.sync_journal().then(move |synced| {
+        if let Err(e) = synced {
+            error!("{:?}", e);
+        }
+        res
+    })))
 }
 
 #[allow(unused_variables)]
-fn bot_on_message<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message) -> BoxFuture<'a, ()> {
+fn bot_on_message<'a>(tg: &mut Telegram, state: &State, archive: Option<&Archive>, config: &Config, username: &str, update_id: i64, msg: &Message) -> BoxFuture<'a, ()> {
+    if is_rikka(config, &msg.from) {
+        // Everything Rikka sends counts as activity
+        stats::record_activity(state, msg.date);
+    }
+    if let Some(ref user) = msg.from {
+        stats::record_user_message(state, msg.chat.id, user);
+    }
+
     if let Some(ref text) = msg.text {
         // A text message
         if text.starts_with("/") {
@@ -93,6 +329,16 @@ fn bot_on_message<'a>(tg: &mut Telegram, state: &State, config: &Config, usernam
             // And see what the command is.
             let cmd_name = cmd.replacen("/", "", 1).replace(username_tail, "");
             info!("Command invoked: /{} from message {}", cmd_name, msg.message_id);
+
+            // Nobody is waiting for the answer to a command sent long before
+            // e.g. while we were down. The stickers are still recorded.
+            if let Some(max_age) = config.max_command_age_secs {
+                let age = time::get_time().sec - msg.date;
+                if age > max_age {
+                    info!("Ignoring /{} sent {} seconds ago", cmd_name, age);
+                    return utils::return_empty();
+                }
+            }
             
             // Find the implementation of the invoked command
             let cmd_map = command_map();
@@ -101,27 +347,250 @@ fn bot_on_message<'a>(tg: &mut Telegram, state: &State, config: &Config, usernam
             } else {
                 warn!("Unkown command: /{}", cmd_name);
             }
+            return utils::return_empty();
         }
-    } else if let Some(ref sticker) = msg.sticker {
+    } else if let Some(media) = message_media(msg) {
         if is_rikka(config, &msg.from) {
-            info!("Sticker from Rikka! ID: {}", sticker.file_id);
-            // Write to state
-            let key = format!("sticker_{}", sticker.file_id);
-            let num: i64 = state.get(&key).unwrap_or(0) + 1;
-            info!("Recorded use of sticker {}: {}", sticker.file_id, num);
-            state.put(&key, &num);
-            let total: i64 = state.get("sticker_total").unwrap_or(0) + 1;
-            info!("Recorded total stickers: {}", total);
-            state.put("sticker_total", &total);
-            return state.save_if_needed();
+            info!("{} from Rikka! ID: {}", media.kind, media.file_id);
+            if !stats::record_media_once(state, update_id, msg.chat.id, msg.date, media.kind, media.file_id) {
+                info!("Already recorded update {}", update_id);
+            }
+            if let Some(ref sticker) = msg.sticker {
+                // Remember the emoji and the set for matching stickers
+                // with emojis (auto reply) and text (inline mode)
+                if let Some(ref emoji) = sticker.emoji {
+                    stats::record_sticker_emoji(state, media.file_id, emoji);
+                }
+                if let Some(ref set_name) = sticker.set_name {
+                    stats::record_sticker_set(state, media.file_id, set_name);
+                }
+            }
+            if let Some(archive) = archive {
+                if ARCHIVED_KINDS.contains(&media.kind) {
+                    return Box::new(state.save_if_needed()
+                        .join(archive.record(tg, media.kind, media.file_id, media.unique_id,
+                            media.extension, msg.chat.id, msg.date))
+                        .join(auto_reply(tg, state, username, msg))
+                        .map(|_| ()));
+                }
+            }
         }
     }
-    utils::return_empty()
+    // Rikka may be replying to us at the same time
+    Box::new(state.save_if_needed()
+        .join(auto_reply(tg, state, username, msg))
+        .map(|_| ()))
+}
+
+#[allow(unused_variables)]
+fn bot_on_callback<'a>(tg: &mut Telegram, state: &State, callbacks: &Callbacks, config: &Config, query: &CallbackQuery) -> BoxFuture<'a, ()> {
+    // Always answer the query, or the client will keep showing a spinner
+    let answer = tg.answer_callback_query(&query.id, None);
+    let msg = match query.message {
+        Some(ref msg) => msg,
+        None => return Box::new(answer.map(|_| ()))
+    };
+    let data = query.data.clone().unwrap_or_default();
+    let args: Vec<&str> = data.split(':').collect();
+    info!("Callback invoked: {} from message {}", data, msg.message_id);
+
+    // Find the handler of the route
+    let fut = match callbacks.get(args[0]) {
+        Some(handler) => handler(tg, state, config, query, msg, args),
+        None => {
+            warn!("Unknown callback data: {}", data);
+            utils::return_empty()
+        }
+    };
+    Box::new(answer.join(fut).map(|_| ()))
+}
+
+/*
+ * Inline mode: offer the stickers Rikka uses the most
+ * The query can be an emoji, or text to match the sticker set names.
+ * Stickers the user picked before come first.
+ * The offset of each page is the index of its first sticker.
+ */
+fn bot_on_inline_query<'a>(tg: &mut Telegram, state: &State, query: &InlineQuery) -> BoxFuture<'a, ()> {
+    let text = query.query.trim();
+    let emoji = normalize_emoji(text);
+    let keyword = text.to_lowercase();
+    let mut stickers: Vec<(String, String, i64, i64)> = stats::media_counts(state, Scope::Global, MEDIA_STICKER, Window::All)
+        .into_iter()
+        .filter(|&(ref id, _)| {
+            if text.is_empty() {
+                true
+            } else if is_lone_emoji(text) {
+                stats::sticker_emoji(state, id).map_or(false, |e| normalize_emoji(&e) == emoji)
+            } else {
+                stats::sticker_set(state, id).map_or(false, |set| set.to_lowercase().contains(&keyword))
+            }
+        })
+        .map(|(id, count)| {
+            let result_id = format!("{:016x}", utils::fnv1a(&id));
+            let chosen = stats::inline_choices(state, query.from.id, &result_id);
+            (id, result_id, chosen, count)
+        })
+        .collect();
+    // Sort is stable, so the counts still decide among the equally chosen
+    stickers.sort_by(|&(_, _, c1, _), &(_, _, c2, _)| c2.cmp(&c1));
+
+    let offset = query.offset.parse::<usize>().unwrap_or(0);
+    let results: Vec<InlineQueryResult> = stickers.iter()
+        .skip(offset)
+        .take(INLINE_PAGE_SIZE)
+        .map(|&(ref id, ref result_id, _, _)| InlineQueryResult::CachedSticker {
+            id: result_id.clone(),
+            sticker_file_id: id.clone()
+        })
+        .collect();
+    // The offset comes from the client, and can be anything
+    let next = offset.saturating_add(INLINE_PAGE_SIZE);
+    let next_offset = if next < stickers.len() {
+        next.to_string()
+    } else {
+        String::new()
+    };
+    Box::new(tg.answer_inline_query(&query.id, &results, &next_offset, true, INLINE_CACHE_TIME)
+        .map(|_| ()))
+}
+
+/*
+ * Passive behaviour: answer with a sticker from Rikka
+ * when we are mentioned, replied to, or sent a lone emoji.
+ * Switched on per chat, and only fires with the
+ * per-chat probability (see `/autoreply`)
+ */
+fn auto_reply<'a>(tg: &mut Telegram, state: &State, username: &str, msg: &Message) -> BoxFuture<'a, ()> {
+    if !state.get::<bool>(&format!("autoreply_{}", msg.chat.id)).unwrap_or(false) {
+        return utils::return_empty();
+    }
+
+    let text = msg.text.as_ref().map(|t| t.trim()).unwrap_or("");
+    let mentioned = text.contains(&format!("@{}", username));
+    let replied = msg.reply_to_message.as_ref()
+        .map_or(false, |m| is_user(&m.from, username));
+    let emoji = if is_lone_emoji(text) { Some(normalize_emoji(text)) } else { None };
+    if !mentioned && !replied && emoji.is_none() {
+        return utils::return_empty();
+    }
+
+    let prob: f64 = state.get(&format!("autoreply_prob_{}", msg.chat.id))
+        .unwrap_or(DEFAULT_AUTOREPLY_PROB);
+    if rand::thread_rng().gen::<f64>() >= prob {
+        return utils::return_empty();
+    }
+
+    // Prefer a sticker that Rikka used with the same emoji
+    let sticker_id = emoji
+        .and_then(|e| stats::random_media_where(state, MEDIA_STICKER, |id| {
+            stats::sticker_emoji(state, id)
+                .map_or(false, |s| normalize_emoji(&s) == e)
+        }))
+        .or_else(|| stats::random_media(state, MEDIA_STICKER));
+    if let None = sticker_id {
+        return utils::return_empty();
+    }
+    info!("Auto replying to message {} in chat {}", msg.message_id, msg.chat.id);
+    Box::new(tg.post("sendSticker", params!{
+        "chat_id" => msg.chat.id,
+        "reply_to_message_id" => msg.message_id,
+        "sticker" => sticker_id.unwrap()
+    }).map(|_| ()))
+}
+
+/*
+ * Whether a text consists of nothing but an emoji
+ * A single one (with modifiers and joiners) is only
+ * a few code points long, all in the emoji blocks.
+ */
+fn is_lone_emoji(text: &str) -> bool {
+    text.chars().count() <= 8
+        && text.chars().any(is_emoji)
+        && text.chars().all(|c| is_emoji(c) || is_emoji_modifier(c))
+}
+
+// The blocks emojis come from, roughly
+fn is_emoji(c: char) -> bool {
+    match c as u32 {
+        0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2300..=0x23FF | 0x2B00..=0x2BFF
+            | 0x2190..=0x21FF | 0x25A0..=0x25FF | 0x24C2 | 0x3030 | 0x303D | 0x3297 | 0x3299
+            | 0xA9 | 0xAE | 0x203C | 0x2049 | 0x2122 | 0x2139 => true,
+        _ => false
+    }
+}
+
+// Joiners, variation selectors, keycaps and tags
+fn is_emoji_modifier(c: char) -> bool {
+    match c as u32 {
+        0x200D | 0xFE0E | 0xFE0F | 0x20E3 | 0xE0020..=0xE007F => true,
+        _ => false
+    }
+}
+
+// Strip variation selectors so that e.g. "❤" matches "❤️"
+fn normalize_emoji(emoji: &str) -> String {
+    emoji.chars().filter(|&c| c != '\u{fe0f}').collect()
+}
+
+/*
+ * A recordable media in a message
+ * Unlike the file_id, the unique_id of a file
+ * is the same for every bot and never changes.
+ */
+struct Media<'m> {
+    kind: &'static str,
+    file_id: &'m str,
+    unique_id: &'m str,
+    // For the file in the archive
+    extension: &'static str
+}
+
+/*
+ * Extract the recordable media from a message
+ */
+fn message_media(msg: &Message) -> Option<Media> {
+    // Fall back to the file_id if there is no file_unique_id
+    fn media<'m>(kind: &'static str, file_id: &'m str, unique_id: &'m Option<String>, extension: &'static str) -> Media<'m> {
+        Media {
+            kind,
+            file_id,
+            unique_id: unique_id.as_ref().map_or(file_id, |id| id),
+            extension
+        }
+    }
+
+    if let Some(ref sticker) = msg.sticker {
+        let extension = if sticker.is_animated.unwrap_or(false) {
+            "tgs"
+        } else if sticker.is_video.unwrap_or(false) {
+            "webm"
+        } else {
+            "webp"
+        };
+        Some(media(MEDIA_STICKER, &sticker.file_id, &sticker.file_unique_id, extension))
+    } else if let Some(ref animation) = msg.animation {
+        Some(media(MEDIA_ANIMATION, &animation.file_id, &animation.file_unique_id, "mp4"))
+    } else if let Some(ref photo) = msg.photo {
+        // Telegram sends every available size of a photo
+        // Only the largest one is worth recording.
+        photo.iter()
+            .max_by_key(|p| p.width * p.height)
+            .map(|p| media(MEDIA_PHOTO, &p.file_id, &p.file_unique_id, "jpg"))
+    } else if let Some(ref voice) = msg.voice {
+        Some(media(MEDIA_VOICE, &voice.file_id, &voice.file_unique_id, "ogg"))
+    } else {
+        None
+    }
 }
 
 fn is_rikka(config: &Config, usr: &Option<User>) -> bool {
+    is_user(usr, &config.rikka_name)
+}
+
+fn is_user(usr: &Option<User>, name: &str) -> bool {
     let res = usr.as_ref().and_then(|user| user.username.as_ref())
-        .and_then(|username| Some(username == &config.rikka_name));
+        .and_then(|username| Some(username == name));
     match res {
         Some(result) => result,
         None => false
@@ -130,20 +599,20 @@ fn is_rikka(config: &Config, usr: &Option<User>) -> bool {
 
 #[allow(unused_variables)]
 fn cmd_hello<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
-    Box::new(tg.post("sendMessage", params!{
-        "chat_id" => msg.chat.id,
-        "reply_to_message_id" => msg.message_id,
-        "text" => "Hello, Rikka Rikka Ri~"
-    }).map(|_| ()))
+    let text = Text::new().plain("Hello, ").bold("Rikka Rikka Ri~");
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
 }
 
 #[allow(unused_variables)]
 fn cmd_help<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
-    Box::new(tg.post("sendMessage", params!{
-        "chat_id" => msg.chat.id,
-        "reply_to_message_id" => msg.message_id,
-        "text" => HELP_STR
-    }).map(|_| ()))
+    // Highlight the command of every `/command - description` line
+    let text = HELP_STR.lines().fold(Text::new(), |text, line| {
+        match line.find(" - ") {
+            Some(i) if line.starts_with("/") => text.bold(&line[..i]).plain(&line[i..]),
+            _ => text.plain(line)
+        }.newline()
+    });
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
 }
 
 // Hidden command: print available commands for use with BotFather
@@ -153,74 +622,573 @@ fn cmd_print_cmds<'a>(tg: &mut Telegram, state: &State, config: &Config, usernam
         .filter(|l| l.starts_with("/"))
         .map(|l| String::from(&l[1..]))
         .collect();
-    Box::new(tg.post("sendMessage", params!{
-        "chat_id" => msg.chat.id,
-        "reply_to_message_id" => msg.message_id,
-        "text" => cmds.join("\n")
-    }).map(|_| ()))
+    let text = Text::new().pre(&cmds.join("\n"), None);
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
 }
 
 #[allow(unused_variables)]
 fn cmd_ping<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
     let t = time::now_utc().to_timespec();
-    Box::new(tg.post("sendMessage", params!{
-        "chat_id" => msg.chat.id,
-        "reply_to_message_id" => msg.message_id,
-        "text" => format!("Latency: {}ms", t.sec * 1000 + (t.nsec as i64) / 1000 / 1000 - msg.date * 1000)
-    }).map(|_| ()))
+    let latency = t.sec * 1000 + (t.nsec as i64) / 1000 / 1000 - msg.date * 1000;
+    let text = Text::new().plain("Latency: ").bold(&format!("{}ms", latency));
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
 }
 
 #[allow(unused_variables)]
 fn cmd_stats<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
-    Box::new(tg.post("sendMessage", params!{
-        "chat_id" => msg.chat.id,
-        "reply_to_message_id" => msg.message_id,
-        "text" => format!("```\n{}\n```", state.to_json()),
-        "parse_mode" => "markdown"
-    }).map(|_| ()))
+    // `/stats [chart [top|usage|users]] [global] [7d|30d|all]`, in any order
+    let mut scope = Scope::Chat(msg.chat.id);
+    let mut window = Window::All;
+    let mut chart = None;
+    for arg in args.iter().skip(1).map(|a| a.trim()) {
+        if arg == "global" {
+            scope = Scope::Global;
+        } else if arg == "chart" {
+            chart = Some("top");
+        } else if chart.is_some() && STATS_CHARTS.contains(&arg) {
+            chart = Some(arg);
+        } else if let Some(w) = parse_window(arg) {
+            window = w;
+        }
+    }
+    if let Some(chart) = chart {
+        return stats_chart(tg, state, msg, scope, window, chart);
+    }
+    let (text, keyboard) = render_stats(state, scope, window, 0);
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), Some(&keyboard)))
 }
 
 /*
- * Choose a random sticker
- * based on the rate of appearance of all the 
- * recorded stickers sent by Rikka.
- * return None if error occurred.
+ * Render a chart of the statistics and send it as a photo
+ * The legend goes into the caption.
  */
-fn random_sticker(state: &State) -> Option<String> {
-    let total: i64 = state.get("sticker_total").unwrap_or(0);
-    if total == 0 {
-        return None;
-    }
-    let rnd_target = rand::thread_rng().gen_range(0, total);
-    let mut records: Vec<(String, i64)> = state.keys().into_iter()
-        .filter(|k| k.starts_with("sticker_") && k != "sticker_total")
-        .map(|k| (k.clone(), state.get::<i64>(&k).unwrap()))
-        .collect();    
-    records.sort_by(|&(_, v1), &(_, v2)| {
-        if v1 < v2 {
-            Ordering::Less
-        } else {
-            Ordering::Greater
+fn stats_chart<'a>(tg: &mut Telegram, state: &State, msg: &Message, scope: Scope, window: Window, chart: &str) -> BoxFuture<'a, ()> {
+    let (png, caption) = match chart {
+        "usage" => {
+            let days = match window {
+                Window::Days(days) => days,
+                Window::All => DEFAULT_CHART_DAYS
+            };
+            let totals = stats::daily_totals(state, scope, MEDIA_STICKER, days);
+            (render::line_chart(&totals), format!("Stickers from Rikka per day, over the last {} days", days))
+        },
+        "users" => {
+            let counts: Vec<(String, i64)> = stats::media_counts(state, scope, stats::USER, Window::All)
+                .into_iter().take(STATS_PAGE_SIZE).collect();
+            let legend: Vec<String> = counts.iter().enumerate()
+                .map(|(i, &(ref id, count))| format!("{}. {} ({})",
+                    i + 1, stats::user_name(state, id).unwrap_or_else(|| id.clone()), count))
+                .collect();
+            let values: Vec<i64> = counts.iter().map(|&(_, v)| v).collect();
+            (render::bar_chart(&values), format!("Most active users\n{}", legend.join("\n")))
+        },
+        _ => {
+            let counts: Vec<(String, i64)> = stats::media_counts(state, scope, MEDIA_STICKER, window)
+                .into_iter().take(STATS_PAGE_SIZE).collect();
+            let legend: Vec<String> = counts.iter().enumerate()
+                .map(|(i, &(ref id, count))| format!("{}. {} ({})",
+                    i + 1, stats::sticker_emoji(state, id).unwrap_or_default(), count))
+                .collect();
+            let values: Vec<i64> = counts.iter().map(|&(_, v)| v).collect();
+            (render::bar_chart(&values), format!("Top stickers of Rikka\n{}", legend.join("\n")))
         }
+    };
+    match png {
+        Ok(png) => respond(tg, state, msg,
+            tg.send_photo(msg.chat.id, InputFile::Memory(String::from("chart.png"), png), &caption)),
+        Err(e) => Box::new(future::err(e))
+    }
+}
+
+#[allow(unused_variables)]
+fn cmd_trending<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let scope = match args.get(1).map(|a| a.trim()) {
+        Some("global") => Scope::Global,
+        _ => Scope::Chat(msg.chat.id)
+    };
+    let trending = stats::trending(state, scope, MEDIA_STICKER, TRENDING_RECENT_DAYS, TRENDING_BASELINE_DAYS);
+    let text = if trending.is_empty() {
+        Text::new().plain("Nothing is trending right now.")
+    } else {
+        trending.iter().take(STATS_PAGE_SIZE).enumerate()
+            .fold(Text::new().bold("Trending stickers:"), |text, (i, &(ref id, count, ratio))| text
+                .newline()
+                .plain(&format!("{}. {} {} times in the last {} days ",
+                    i + 1, stats::sticker_emoji(state, id).unwrap_or_default(), count, TRENDING_RECENT_DAYS))
+                .italic(&format!("({:.1}x the usual)", ratio)))
+    };
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
+}
+
+#[allow(unused_variables)]
+fn cmd_when<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let offset = config.utc_offset_minutes.unwrap_or(0);
+    let heatmap = stats::activity_heatmap(state, offset);
+    let text = Text::new().pre(&render_heatmap(&heatmap, offset), None);
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
+}
+
+/*
+ * Render the activity heatmap as a text grid
+ * One row per weekday and one column per hour,
+ * shaded relative to the busiest hour.
+ */
+fn render_heatmap(heatmap: &[[i64; 24]; 7], offset: i64) -> String {
+    let max = heatmap.iter().flat_map(|day| day.iter()).cloned().max().unwrap_or(0);
+    let total: i64 = heatmap.iter().flat_map(|day| day.iter()).sum();
+    let zone = format!("UTC{}{:02}:{:02}", if offset < 0 { "-" } else { "+" }, offset.abs() / 60, offset.abs() % 60);
+    if max == 0 {
+        return format!("No activity recorded yet. ({})", zone);
+    }
+
+    let mut lines = vec![String::from("    0     6     12    18    ")];
+    let mut busiest = (0, 0);
+    for (day, hours) in heatmap.iter().enumerate() {
+        let row: String = hours.iter().enumerate().map(|(hour, &count)| {
+            if count > heatmap[busiest.0][busiest.1] {
+                busiest = (day, hour);
+            }
+            // Anything above zero is at least lightly shaded
+            let level = (count * (HEATMAP_SHADES.len() as i64 - 1) + max - 1) / max;
+            HEATMAP_SHADES[level as usize]
+        }).collect();
+        lines.push(format!("{} {}", WEEKDAYS[day], row));
+    }
+    lines.push(String::new());
+    lines.push(format!("{} messages, busiest on {} {:02}:00 ({})",
+        total, WEEKDAYS[busiest.0], busiest.1, zone));
+    lines.join("\n")
+}
+
+// `<n>d`, up to a year, or `all`
+fn parse_window(arg: &str) -> Option<Window> {
+    if arg == "all" {
+        Some(Window::All)
+    } else if arg.ends_with("d") {
+        arg[..arg.len() - 1].parse::<i64>().ok()
+            .and_then(|days| if days > 0 && days <= stats::MAX_WINDOW_DAYS { Some(Window::Days(days)) } else { None })
+    } else {
+        None
+    }
+}
+
+// Hidden command: dump the raw state. Only for the owner.
+#[allow(unused_variables)]
+fn cmd_dump<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    if !config.owner_name.as_ref().map_or(false, |owner| is_user(&msg.from, owner)) {
+        warn!("/dump invoked by someone other than the owner: {:?}", msg.from);
+        return utils::return_empty();
+    }
+    let text = Text::new().pre(&state.to_json(), Some("json"));
+    respond(tg, state, msg, tg.send_message(msg.chat.id, &text, Some(msg.message_id), None))
+}
+
+// Scopes are encoded as `g` or `c` in callback data
+// The chat is always the one the message belongs to.
+// Windows are encoded the same way as the arguments.
+fn scope_to_data(scope: Scope) -> &'static str {
+    match scope {
+        Scope::Global => "g",
+        Scope::Chat(_) => "c"
+    }
+}
+
+fn scope_from_data(data: Option<&&str>, chat_id: i64) -> Scope {
+    match data {
+        Some(&"g") => Scope::Global,
+        _ => Scope::Chat(chat_id)
+    }
+}
+
+fn window_to_data(window: Window) -> String {
+    match window {
+        Window::All => String::from("all"),
+        Window::Days(days) => format!("{}d", days)
+    }
+}
+
+fn window_from_data(data: Option<&&str>) -> Window {
+    data.and_then(|w| parse_window(w)).unwrap_or(Window::All)
+}
+
+/*
+ * Render one page of the sticker leaderboard
+ * Returns the text and the inline keyboard to go with it:
+ * one button to send each sticker on the page,
+ * plus navigation and switches between views and windows.
+ */
+fn render_stats(state: &State, scope: Scope, window: Window, page: usize) -> (Text, InlineKeyboardMarkup) {
+    let counts = stats::media_counts(state, scope, MEDIA_STICKER, window);
+    let pages = cmp::max(1, (counts.len() + STATS_PAGE_SIZE - 1) / STATS_PAGE_SIZE);
+    let page = cmp::min(page, pages - 1);
+    let total = stats::total(state, scope, MEDIA_STICKER, window);
+    let s = scope_to_data(scope);
+    let w = window_to_data(window);
+
+    let mut text = Text::new().bold(match scope {
+        Scope::Global => "Rikka everywhere",
+        Scope::Chat(_) => "Rikka in this chat"
     });
-    let mut acc: i64 = 0;
-    for (k, v) in records {
-        acc += v;
-        if acc >= rnd_target {
-            return Some(k.replace("sticker_", ""));
+    if let Window::Days(days) = window {
+        text = text.italic(&format!(", last {} days", days));
+    }
+    text = text.newline().plain(&MEDIA_KINDS.iter()
+        .map(|kind| format!("{}: {}", media_name(kind), stats::total(state, scope, kind, window)))
+        .collect::<Vec<String>>()
+        .join(" | "));
+    text = text.newline().newline();
+
+    let mut send_buttons = vec![];
+    if counts.is_empty() {
+        text = text.plain("No stickers recorded yet.");
+    } else {
+        text = text.bold(&format!("Top stickers (page {}/{}):", page + 1, pages)).newline();
+        for (i, &(ref id, count)) in counts.iter().enumerate().skip(page * STATS_PAGE_SIZE).take(STATS_PAGE_SIZE) {
+            text = text.plain(&format!("{}. {} {} times ({:.1}%)\n",
+                i + 1, stats::sticker_emoji(state, id).unwrap_or_default(),
+                count, count as f64 * 100.0 / total as f64));
+            send_buttons.push(InlineKeyboardButton::callback(&format!("{}", i + 1),
+                &format!("stats_send:{}", sticker_key(id))));
         }
     }
-    None
+
+    let mut nav_buttons = vec![];
+    if page > 0 {
+        nav_buttons.push(InlineKeyboardButton::callback("◀", &format!("stats:{}:{}:{}", s, w, page - 1)));
+    }
+    nav_buttons.push(match scope {
+        Scope::Global => InlineKeyboardButton::callback("This chat", &format!("stats:c:{}:0", w)),
+        Scope::Chat(_) => InlineKeyboardButton::callback("Global", &format!("stats:g:{}:0", w))
+    });
+    if page + 1 < pages {
+        nav_buttons.push(InlineKeyboardButton::callback("▶", &format!("stats:{}:{}:{}", s, w, page + 1)));
+    }
+
+    let window_buttons = STATS_WINDOWS.iter()
+        .filter(|&&other| other != window)
+        .map(|&other| InlineKeyboardButton::callback(&window_to_data(other),
+            &format!("stats:{}:{}:0", s, window_to_data(other))))
+        .collect();
+
+    let keyboard = InlineKeyboardMarkup::new()
+        .grid(send_buttons, STATS_BUTTONS_PER_ROW)
+        .row(nav_buttons)
+        .row(window_buttons);
+    (text, keyboard)
+}
+
+fn media_name(kind: &str) -> &'static str {
+    match kind {
+        MEDIA_STICKER => "Stickers",
+        MEDIA_ANIMATION => "GIFs",
+        MEDIA_PHOTO => "Photos",
+        MEDIA_VOICE => "Voice messages",
+        _ => "Others"
+    }
 }
 
+// The buttons under `/stats`, see render_stats
+fn stats_routes(callbacks: &mut Callbacks) {
+    callbacks.route("stats", stats_on_callback);
+    callbacks.route("stats_send", stats_send_on_callback);
+}
+
+// `stats:<scope>:<window>:<page>`: turn to another page of the leaderboard
 #[allow(unused_variables)]
-fn cmd_rikka<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
-    let sticker_id = random_sticker(state);
+fn stats_on_callback<'a>(tg: &mut Telegram, state: &State, config: &Config, query: &CallbackQuery, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let scope = scope_from_data(args.get(1), msg.chat.id);
+    let window = window_from_data(args.get(2));
+    let page = args.get(3).and_then(|p| p.parse::<usize>().ok()).unwrap_or(0);
+    let (text, keyboard) = render_stats(state, scope, window, page);
+    Box::new(tg.edit_message_text(msg.chat.id, msg.message_id, &text, Some(&keyboard))
+        .map(|_| ()))
+}
+
+/*
+ * A short key for a sticker in callback data
+ * File ids are too long to fit in 64 bytes.
+ */
+fn sticker_key(file_id: &str) -> String {
+    format!("{:016x}", utils::fnv1a(file_id))
+}
+
+/*
+ * `stats_send:<key>`: send a sticker from the leaderboard
+ * The key stays the same however the ranking changes.
+ */
+#[allow(unused_variables)]
+fn stats_send_on_callback<'a>(tg: &mut Telegram, state: &State, config: &Config, query: &CallbackQuery, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let sticker_id = args.get(1).and_then(|key| {
+        stats::media_counts(state, Scope::Global, MEDIA_STICKER, Window::All).into_iter()
+            .map(|(id, _)| id)
+            .find(|id| sticker_key(id) == *key)
+    });
     if let None = sticker_id {
         return utils::return_empty();
     }
-    Box::new(tg.post("sendSticker", params!{
-        "chat_id" => msg.chat.id,
-        "sticker" => sticker_id.unwrap()
-    }).map(|_| ()))
-}
\ No newline at end of file
+    Box::new(tg.send_sticker(msg.chat.id, InputFile::FileId(sticker_id.unwrap()))
+        .map(|_| ()))
+}
+
+#[allow(unused_variables)]
+fn cmd_rikka<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    // `/rikka [gif|photo|voice]`, defaults to a sticker
+    let kind = match args.get(1).map(|a| a.trim()) {
+        Some("gif") => MEDIA_ANIMATION,
+        Some("photo") => MEDIA_PHOTO,
+        Some("voice") => MEDIA_VOICE,
+        _ => MEDIA_STICKER
+    };
+    respond(tg, state, msg, send_random_media(tg, state, msg.chat.id, kind))
+}
+
+// The button under `/rikka`, see send_random_media
+fn rikka_routes(callbacks: &mut Callbacks) {
+    callbacks.route("rikka", rikka_on_callback);
+}
+
+/*
+ * `rikka:<kind>`: the button under the last `/rikka`
+ * Moves the button from the old message to a new one.
+ */
+#[allow(unused_variables)]
+fn rikka_on_callback<'a>(tg: &mut Telegram, state: &State, config: &Config, query: &CallbackQuery, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let kind = match args.get(1) {
+        Some(kind) if MEDIA_KINDS.contains(kind) => *kind,
+        _ => return utils::return_empty()
+    };
+    Box::new(tg.edit_message_reply_markup(msg.chat.id, msg.message_id, None)
+        .join(send_random_media(tg, state, msg.chat.id, kind))
+        .map(|_| ()))
+}
+
+/*
+ * Send a random media of a kind from Rikka
+ * with a button under it for another one.
+ * The parameter name of every send method is the same as the kind.
+ */
+fn send_random_media<'a>(tg: &Telegram, state: &State, chat_id: i64, kind: &str) -> BoxFuture<'a, Result> {
+    let method = match kind {
+        MEDIA_ANIMATION => "sendAnimation",
+        MEDIA_PHOTO => "sendPhoto",
+        MEDIA_VOICE => "sendVoice",
+        _ => "sendSticker"
+    };
+    let file_id = stats::random_media(state, kind);
+    if let None = file_id {
+        return Box::new(future::ok(Result::Nothing));
+    }
+    let keyboard = InlineKeyboardMarkup::new()
+        .button(InlineKeyboardButton::callback("🔁 Another one", &format!("rikka:{}", kind)));
+    Box::new(tg.post(method, params!{
+        "chat_id" => chat_id,
+        kind => file_id.unwrap(),
+        "reply_markup" => keyboard
+    }))
+}
+
+/*
+ * Whether the sender of a message may change the settings of its chat
+ * That is the owner, the admins of a group, or anyone in a private chat.
+ */
+fn can_configure<'a>(tg: &Telegram, config: &Config, msg: &Message) -> BoxFuture<'a, bool> {
+    let is_owner = config.owner_name.as_ref().map_or(false, |owner| is_user(&msg.from, owner));
+    if is_owner || msg.chat.chat_type.as_ref().map_or(false, |t| t == "private") {
+        return Box::new(future::ok(true));
+    }
+    let user_id = match msg.from {
+        Some(ref user) => user.id,
+        None => return Box::new(future::ok(false))
+    };
+    Box::new(tg.get_chat_member(msg.chat.id, user_id)
+        .map(|result| match result {
+            Result::ChatMember(member) => member.status == "creator" || member.status == "administrator",
+            _ => false
+        }))
+}
+
+/*
+ * Change a setting of the chat of `msg` with `apply`
+ * once the sender is known to be allowed to,
+ * and reply with the text it returns
+ */
+fn configure<'a, F>(tg: &Telegram, state: &State, config: &Config, msg: &Message, apply: F) -> BoxFuture<'a, ()>
+    where F: FnOnce(&State) -> Text + 'a {
+    let api = tg.api();
+    let state_ = state.clone();
+    let chat_id = msg.chat.id;
+    let message_id = msg.message_id;
+    let response = can_configure(tg, config, msg)
+        .and_then(move |allowed| {
+            if !allowed {
+                let text = Text::new().plain("Only the admins of this chat can change that.");
+                return api.send_message(chat_id, &text, Some(message_id), None);
+            }
+            let text = apply(&state_);
+            Box::new(state_.save_if_needed()
+                .join(api.send_message(chat_id, &text, Some(message_id), None))
+                .map(|(_, result)| result))
+        });
+    respond(tg, state, msg, Box::new(response))
+}
+
+/*
+ * Configure automatic replies for the current chat
+ * `/autoreply on`, `/autoreply off`, or `/autoreply <probability>`
+ * Without arguments, print the current setting.
+ * Changing it is for the admins only.
+ */
+#[allow(unused_variables)]
+fn cmd_autoreply<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let chat_id = msg.chat.id;
+    let arg = match args.get(1).map(|a| a.trim().to_string()) {
+        Some(arg) => arg,
+        None => {
+            let text = autoreply_status(state, chat_id);
+            return respond(tg, state, msg, tg.send_message(chat_id, &text, Some(msg.message_id), None));
+        }
+    };
+    configure(tg, state, config, msg, move |state| {
+        let enabled_key = format!("autoreply_{}", chat_id);
+        let prob_key = format!("autoreply_prob_{}", chat_id);
+        match arg.as_str() {
+            "on" => state.put(&enabled_key, &true),
+            "off" => state.put(&enabled_key, &false),
+            arg => match arg.parse::<f64>() {
+                Ok(prob) if prob >= 0.0 && prob <= 1.0 => {
+                    state.put(&enabled_key, &true);
+                    state.put(&prob_key, &prob);
+                },
+                _ => return Text::new().plain("Usage: ").code("/autoreply [on|off|<probability between 0 and 1>]")
+            }
+        }
+        autoreply_status(state, chat_id)
+    })
+}
+
+fn autoreply_status(state: &State, chat_id: i64) -> Text {
+    let enabled: bool = state.get(&format!("autoreply_{}", chat_id)).unwrap_or(false);
+    let prob: f64 = state.get(&format!("autoreply_prob_{}", chat_id)).unwrap_or(DEFAULT_AUTOREPLY_PROB);
+    if enabled {
+        Text::new().plain("Auto reply is ").bold("on").plain(&format!(", with a probability of {}.", prob))
+    } else {
+        Text::new().plain("Auto reply is ").bold("off").plain(".")
+    }
+}
+
+/*
+ * Configure automatic deletion of command responses in the current chat
+ * `/autodelete <seconds>`, `/autodelete <seconds> all` to delete
+ * the commands as well (needs admin rights), or `/autodelete off`
+ * Without arguments, print the current setting.
+ * Changing it is for the admins only.
+ */
+#[allow(unused_variables)]
+fn cmd_autodelete<'a>(tg: &mut Telegram, state: &State, config: &Config, username: &str, msg: &Message, args: Vec<&str>) -> BoxFuture<'a, ()> {
+    let chat_id = msg.chat.id;
+    let arg = match args.get(1).map(|a| a.trim().to_string()) {
+        Some(arg) => arg,
+        None => {
+            let text = autodelete_status(state, chat_id);
+            return respond(tg, state, msg, tg.send_message(chat_id, &text, Some(msg.message_id), None));
+        }
+    };
+    let all = args.get(2).map(|a| a.trim()) == Some("all");
+    configure(tg, state, config, msg, move |state| {
+        let delay_key = format!("autodelete_{}", chat_id);
+        let commands_key = format!("autodelete_cmd_{}", chat_id);
+        match arg.as_str() {
+            "off" => state.put(&delay_key, &0),
+            arg => match arg.parse::<i64>() {
+                Ok(delay) if delay > 0 && delay <= MAX_AUTODELETE_DELAY => {
+                    state.put(&delay_key, &delay);
+                    state.put(&commands_key, &all);
+                },
+                _ => return Text::new().plain("Usage: ")
+                    .code(&format!("/autodelete [off|<seconds, at most {}> [all]]", MAX_AUTODELETE_DELAY))
+            }
+        }
+        autodelete_status(state, chat_id)
+    })
+}
+
+fn autodelete_status(state: &State, chat_id: i64) -> Text {
+    let delay: i64 = state.get(&format!("autodelete_{}", chat_id)).unwrap_or(0);
+    let commands: bool = state.get(&format!("autodelete_cmd_{}", chat_id)).unwrap_or(false);
+    if delay <= 0 {
+        Text::new().plain("Auto delete is ").bold("off").plain(".")
+    } else if commands {
+        Text::new().plain("Commands and my replies to them will be deleted after ")
+            .bold(&format!("{} seconds", delay)).plain(".")
+    } else {
+        Text::new().plain("My replies to commands will be deleted after ")
+            .bold(&format!("{} seconds", delay)).plain(".")
+    }
+}
+
+/*
+ * Send the response to a command
+ * With `/autodelete` on in the chat, the response (and
+ * the command, if asked for) is deleted after the delay.
+ */
+fn respond<'a>(tg: &Telegram, state: &State, msg: &Message, response: BoxFuture<'a, Result>) -> BoxFuture<'a, ()> {
+    let chat_id = msg.chat.id;
+    let delay: i64 = state.get(&format!("autodelete_{}", chat_id)).unwrap_or(0);
+    if delay <= 0 {
+        return Box::new(response.map(|_| ()));
+    }
+    let command_id = if state.get(&format!("autodelete_cmd_{}", chat_id)).unwrap_or(false) {
+        Some(msg.message_id)
+    } else {
+        None
+    };
+    let api = tg.api();
+    let state = state.clone();
+    Box::new(response.and_then(move |result| {
+        let due = time::get_time().sec + delay;
+        match result {
+            Result::Message(ref sent) => cleanup::schedule_delete(&api, &state, chat_id, sent.message_id, due),
+            Result::Messages(ref parts) => for sent in parts {
+                cleanup::schedule_delete(&api, &state, chat_id, sent.message_id, due);
+            },
+            _ => ()
+        }
+        if let Some(command_id) = command_id {
+            cleanup::schedule_delete(&api, &state, chat_id, command_id, due);
+        }
+        state.save_if_needed()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lone_emoji() {
+        assert!(is_lone_emoji("😀"));
+        assert!(is_lone_emoji("❤️"));
+        assert!(is_lone_emoji("👍🏽"));
+        assert!(is_lone_emoji("👨‍👩‍👧"));
+        assert!(is_lone_emoji("🇯🇵"));
+        assert!(!is_lone_emoji(""));
+        assert!(!is_lone_emoji("\u{fe0f}"));
+        assert!(!is_lone_emoji("a😀"));
+        assert!(!is_lone_emoji("😀 "));
+        assert!(!is_lone_emoji("漢字"));
+        assert!(!is_lone_emoji("«»"));
+        assert!(!is_lone_emoji("😀😀😀😀😀😀😀😀😀"));
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(parse_window("all"), Some(Window::All));
+        assert_eq!(parse_window("7d"), Some(Window::Days(7)));
+        assert_eq!(parse_window(&format!("{}d", stats::MAX_WINDOW_DAYS)), Some(Window::Days(stats::MAX_WINDOW_DAYS)));
+        assert_eq!(parse_window(&format!("{}d", stats::MAX_WINDOW_DAYS + 1)), None);
+        assert_eq!(parse_window("0d"), None);
+        assert_eq!(parse_window("-1d"), None);
+        assert_eq!(parse_window("d"), None);
+        assert_eq!(parse_window("7"), None);
+    }
+}
@@ -0,0 +1,76 @@
+use futures::Future;
+use state::State;
+use std::cmp;
+use std::time::Duration;
+use telegram::Api;
+use time;
+
+/*
+ * Delayed deletion of messages
+ * Every pending deletion is kept in State as
+ * `pending_delete_<chat_id>_<message_id>` => unix time when due,
+ * so that it is still carried out after a restart.
+ */
+
+const PENDING_PREFIX: &str = "pending_delete_";
+
+fn pending_key(chat_id: i64, message_id: i64) -> String {
+    format!("{}{}_{}", PENDING_PREFIX, chat_id, message_id)
+}
+
+/*
+ * Delete a message at `due` (unix time)
+ */
+pub fn schedule_delete(api: &Api, state: &State, chat_id: i64, message_id: i64, due: i64) {
+    state.put(&pending_key(chat_id, message_id), &due);
+    spawn_delete(api, state, chat_id, message_id, due);
+}
+
+/*
+ * Schedule again the deletions that were pending when we stopped
+ * The overdue ones are carried out right away.
+ */
+pub fn restore_pending(api: &Api, state: &State) {
+    for k in state.keys() {
+        if !k.starts_with(PENDING_PREFIX) {
+            continue;
+        }
+        // Chat ids can be negative, but never contain `_`
+        let ids: Vec<i64> = k[PENDING_PREFIX.len()..].split('_')
+            .filter_map(|id| id.parse().ok())
+            .collect();
+        match state.get::<i64>(&k) {
+            Some(due) if ids.len() == 2 => {
+                info!("Restoring the deletion of message {} in chat {}", ids[1], ids[0]);
+                spawn_delete(api, state, ids[0], ids[1], due);
+            },
+            _ => {
+                warn!("Dropping malformed pending deletion {}", k);
+                state.remove(&k);
+            }
+        }
+    }
+}
+
+/*
+ * Nothing is lost by not waiting for the delay on shutdown,
+ * as the deletion is restored from State. Only the deletion
+ * itself is, once started.
+ */
+fn spawn_delete(api: &Api, state: &State, chat_id: i64, message_id: i64, due: i64) {
+    let wait = cmp::max(0, due - time::get_time().sec) as u64;
+    let key = pending_key(chat_id, message_id);
+    let state = state.clone();
+    let api_ = api.clone();
+    api.spawn_background(api.delay(Duration::from_secs(wait))
+        .map(move |_| api_.spawn(api_.delete_message(chat_id, message_id)
+            .then(move |res| {
+                // Deleted by someone else, too old, or no permission.
+                // Either way, retrying will not help.
+                if let Err(e) = res {
+                    warn!("Failed to delete message {} in chat {}: {:?}", message_id, chat_id, e);
+                }
+                state.remove(&key);
+                state.save_if_needed()
+            }))));
+}
@@ -0,0 +1,234 @@
+use futures::{future, Future};
+use futures::unsync::oneshot;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+use utils::BoxFuture;
+
+/*
+ * Runs the futures of the updates one chat at a time
+ * in the order of the updates, and up to `max_concurrency`
+ * chats at once.
+ *
+ * Only what the future does once polled is ordered.
+ * The subscribers run as soon as an update is fetched,
+ * so their changes to State, and whatever they start
+ * right away (e.g. `pool.spawn_fn` or cleanup timers),
+ * are not held back by the previous updates.
+ *
+ * At most `max_queue` updates wait for their turn. The
+ * update loop should not fetch more than `room()` updates,
+ * and wait for `until_room()` once there is none.
+ */
+pub struct Dispatcher {
+    inner: Rc<RefCell<Inner>>
+}
+
+struct Inner {
+    handle: Handle,
+    max_concurrency: usize,
+    max_queue: usize,
+    // Waiting futures of each chat, in order
+    queues: HashMap<i64, VecDeque<BoxFuture<'static, ()>>>,
+    // Chats with a future in flight
+    running: HashSet<i64>,
+    // Chats with waiting futures and none in flight
+    ready: VecDeque<i64>,
+    queued: usize,
+    room_waiter: Option<oneshot::Sender<()>>,
+    idle_waiters: Vec<oneshot::Sender<()>>
+}
+
+impl Dispatcher {
+    pub fn new(handle: Handle, max_concurrency: usize, max_queue: usize) -> Dispatcher {
+        Dispatcher {
+            inner: Rc::new(RefCell::new(Inner {
+                handle,
+                max_concurrency,
+                max_queue,
+                queues: HashMap::new(),
+                running: HashSet::new(),
+                ready: VecDeque::new(),
+                queued: 0,
+                room_waiter: None,
+                idle_waiters: vec![]
+            }))
+        }
+    }
+
+    pub fn set_limits(&mut self, max_concurrency: Option<usize>, max_queue: Option<usize>) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(max_concurrency) = max_concurrency {
+            inner.max_concurrency = max_concurrency;
+        }
+        if let Some(max_queue) = max_queue {
+            inner.max_queue = max_queue;
+        }
+    }
+
+    /*
+     * Queue the future of an update from `chat_id`
+     */
+    pub fn dispatch(&self, chat_id: i64, fut: BoxFuture<'static, ()>) {
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.queues.entry(chat_id).or_insert_with(VecDeque::new).push_back(fut);
+            inner.queued += 1;
+            if !inner.running.contains(&chat_id) && !inner.ready.contains(&chat_id) {
+                inner.ready.push_back(chat_id);
+            }
+        }
+        pump(&self.inner);
+    }
+
+    // How many more updates can be queued
+    pub fn room(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.max_queue.saturating_sub(inner.queued)
+    }
+
+    /*
+     * Resolves once there is room in the queue again
+     */
+    pub fn until_room<'a>(&self) -> BoxFuture<'a, ()> {
+        if self.room() > 0 {
+            return Box::new(future::ok(()));
+        }
+        warn!("Too many updates waiting. Pausing until some are done.");
+        let (tx, rx) = oneshot::channel();
+        self.inner.borrow_mut().room_waiter = Some(tx);
+        Box::new(rx.map_err(|_| "Dispatcher is gone".into()))
+    }
+
+    /*
+     * Resolves once every future dispatched so far is done
+     */
+    pub fn until_idle<'a>(&self) -> BoxFuture<'a, ()> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.is_idle() {
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        inner.idle_waiters.push(tx);
+        Box::new(rx.map_err(|_| "Dispatcher is gone".into()))
+    }
+}
+
+impl Inner {
+    fn is_idle(&self) -> bool {
+        self.queued == 0 && self.running.is_empty()
+    }
+}
+
+/*
+ * Start the futures of as many ready chats as allowed
+ */
+fn pump(inner_rc: &Rc<RefCell<Inner>>) {
+    let (handle, started) = {
+        let mut inner = inner_rc.borrow_mut();
+        let mut started = vec![];
+        while inner.running.len() < inner.max_concurrency {
+            let chat_id = match inner.ready.pop_front() {
+                Some(chat_id) => chat_id,
+                None => break
+            };
+            if let Some(fut) = inner.queues.get_mut(&chat_id).and_then(|queue| queue.pop_front()) {
+                inner.queued -= 1;
+                inner.running.insert(chat_id);
+                started.push((chat_id, fut));
+            }
+        }
+        if inner.queued < inner.max_queue {
+            if let Some(tx) = inner.room_waiter.take() {
+                let _ = tx.send(());
+            }
+        }
+        if inner.is_idle() {
+            for tx in inner.idle_waiters.drain(..) {
+                let _ = tx.send(());
+            }
+        }
+        (inner.handle.clone(), started)
+    };
+
+    // Spawned without holding the borrow
+    for (chat_id, fut) in started {
+        let weak = Rc::downgrade(inner_rc);
+        handle.spawn(fut.then(move |res| {
+            if let Err(e) = res {
+                warn!("Error suppressed: {:?}", e);
+            }
+            if let Some(inner_rc) = weak.upgrade() {
+                {
+                    let mut inner = inner_rc.borrow_mut();
+                    inner.running.remove(&chat_id);
+                    if inner.queues.get(&chat_id).map_or(false, |queue| !queue.is_empty()) {
+                        inner.ready.push_back(chat_id);
+                    } else {
+                        inner.queues.remove(&chat_id);
+                    }
+                }
+                pump(&inner_rc);
+            }
+            Ok(())
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio_core::reactor::{Core, Timeout};
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // Logs its start and its end, `ms` apart
+    fn job(handle: &Handle, log: &Log, name: &'static str, ms: u64) -> BoxFuture<'static, ()> {
+        let handle = handle.clone();
+        let log = log.clone();
+        Box::new(future::lazy(move || {
+            log.borrow_mut().push(format!("{} start", name));
+            Timeout::new(Duration::from_millis(ms), &handle).unwrap()
+                .map(move |_| log.borrow_mut().push(format!("{} end", name)))
+                .map_err(|e| e.into())
+        }))
+    }
+
+    fn run(max_concurrency: usize, jobs: &[(i64, &'static str, u64)]) -> Vec<String> {
+        let mut core = Core::new().unwrap();
+        let log: Log = Rc::new(RefCell::new(vec![]));
+        let dispatcher = Dispatcher::new(core.handle(), max_concurrency, 100);
+        for &(chat_id, name, ms) in jobs {
+            dispatcher.dispatch(chat_id, job(&core.handle(), &log, name, ms));
+        }
+        core.run(dispatcher.until_idle()).unwrap();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn one_chat_at_a_time() {
+        let log = run(4, &[(1, "a1", 50), (1, "a2", 0), (2, "b1", 10)]);
+        assert_eq!(log, vec!["a1 start", "b1 start", "b1 end", "a1 end", "a2 start", "a2 end"]);
+    }
+
+    #[test]
+    fn chats_up_to_max_concurrency() {
+        let log = run(1, &[(1, "a1", 20), (2, "b1", 0), (1, "a2", 0)]);
+        assert_eq!(log, vec!["a1 start", "a1 end", "b1 start", "b1 end", "a2 start", "a2 end"]);
+    }
+
+    #[test]
+    fn room_in_the_queue() {
+        let core = Core::new().unwrap();
+        let dispatcher = Dispatcher::new(core.handle(), 1, 2);
+        assert_eq!(dispatcher.room(), 2);
+        // The first one starts right away, the others wait
+        for _ in 0..3 {
+            dispatcher.dispatch(1, Box::new(future::empty()));
+        }
+        assert_eq!(dispatcher.room(), 0);
+    }
+}
@@ -0,0 +1,375 @@
+use errors::*;
+use serde_json;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/*
+ * The changes to a State since its last snapshot
+ * One JSON entry per line, appended as they happen,
+ * in `<state_file>.journal`.
+ *
+ * When a snapshot is taken, the journal is moved aside
+ * as `<state_file>.journal.<generation>` and a new one
+ * started. Once the snapshot is on disk, the journals
+ * moved aside up to then are no longer needed.
+ *
+ * Replaying an entry that is already in the snapshot
+ * does no harm, since every entry sets or removes a key.
+ *
+ * Every journal starts with the generation it follows,
+ * so that a snapshot older than the journals (e.g. a backup)
+ * can be told apart.
+ */
+pub struct Journal {
+    path: String,
+    file: Option<File>,
+    generation: u64,
+    // fsync the entries, in batches, see `take_unsynced`
+    sync: bool,
+    // Appended to since the last `take_unsynced`
+    unsynced: bool
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Entry {
+    Put(String, String),
+    Remove(String),
+    // First in every journal: everything up to this generation is in the snapshot
+    Start(u64)
+}
+
+impl Entry {
+    fn apply(self, map: &mut HashMap<String, String>) {
+        match self {
+            Entry::Put(key, value) => { map.insert(key, value); },
+            Entry::Remove(key) => { map.remove(&key); },
+            Entry::Start(_) => ()
+        }
+    }
+}
+
+fn journal_file_name(state_file: &str) -> String {
+    format!("{}.journal", state_file)
+}
+
+/*
+ * The journals moved aside, oldest first
+ */
+fn rotated_journals(state_file: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let prefix = format!("{}.", journal_file_name(state_file));
+    let path = Path::new(&prefix);
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let name_prefix = path.file_name().and_then(|n| n.to_str()).unwrap_or("").to_string();
+    let mut ret = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let generation = entry.file_name().to_str()
+            .and_then(|name| if name.starts_with(&name_prefix) { Some(name[name_prefix.len()..].to_string()) } else { None })
+            .and_then(|generation| generation.parse::<u64>().ok());
+        if let Some(generation) = generation {
+            ret.push((generation, entry.path()));
+        }
+    }
+    ret.sort_by_key(|&(generation, _)| generation);
+    Ok(ret)
+}
+
+/*
+ * Apply the entries of one journal file
+ * Returns their number, the generation it follows, and
+ * the byte offset right after the last good line.
+ * A broken last line is what a crash in the middle of an
+ * append leaves behind, and is skipped with the rest.
+ * So is a last line without its line break.
+ */
+fn replay_file(path: &Path, map: &mut HashMap<String, String>) -> Result<(usize, Option<u64>, u64)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, None, 0)),
+        Err(e) => return Err(e.into())
+    };
+    let mut reader = BufReader::new(file);
+    let mut count = 0;
+    let mut start = None;
+    let mut good_len = 0;
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            warn!("Stopping at an unfinished entry in {:?}", path);
+            break;
+        }
+        match serde_json::from_slice::<Entry>(&line) {
+            Ok(Entry::Start(generation)) => start = Some(generation),
+            Ok(entry) => {
+                entry.apply(map);
+                count += 1;
+            },
+            Err(e) => {
+                warn!("Stopping at a broken entry in {:?}: {:?}", path, e);
+                break;
+            }
+        }
+        good_len += read as u64;
+    }
+    Ok((count, start, good_len))
+}
+
+/*
+ * Cut off whatever follows the last good line of
+ * the journal at `path`, before appending to it again.
+ * Otherwise the next entry would be glued to the broken
+ * one, and lost along with everything after it.
+ */
+fn truncate_broken_tail(path: &Path, good_len: u64) -> Result<()> {
+    let file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into())
+    };
+    let len = file.metadata()?.len();
+    if len > good_len {
+        warn!("Dropping the last {} bytes of {:?}", len - good_len, path);
+        file.set_len(good_len)?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+impl Journal {
+    /*
+     * Apply every journal of `state_file` to its snapshot `map`
+     * which covers up to `snapshot_generation`, if known.
+     * Returns the number of entries replayed, and the newest
+     * generation seen, to carry on from.
+     */
+    pub fn replay(state_file: &str, map: &mut HashMap<String, String>, snapshot_generation: Option<u64>) -> Result<(usize, u64)> {
+        let mut count = 0;
+        let mut generation = snapshot_generation.unwrap_or(0);
+        // The generation the oldest journal left follows
+        let mut oldest_start = None;
+        let mut paths: Vec<PathBuf> = vec![];
+        for (gen, path) in rotated_journals(state_file)? {
+            generation = cmp::max(generation, gen);
+            paths.push(path);
+        }
+        // The current journal goes last, and is appended to next
+        let current = PathBuf::from(journal_file_name(state_file));
+        paths.push(current.clone());
+        for path in paths {
+            let (replayed, start, good_len) = replay_file(&path, map)?;
+            count += replayed;
+            if let Some(start) = start {
+                oldest_start = oldest_start.or(Some(start));
+                generation = cmp::max(generation, start);
+            }
+            if path == current {
+                truncate_broken_tail(&path, good_len)?;
+            }
+        }
+        // e.g. the snapshot is a backup from before the last compaction
+        if let (Some(snapshot), Some(start)) = (snapshot_generation, oldest_start) {
+            if start > snapshot {
+                warn!("The state of {} is from generation {}, but its journal starts after generation {}. \
+                    The changes in between are lost.", state_file, snapshot, start);
+            }
+        }
+        if count > 0 {
+            info!("Replayed {} changes from the journal of {}", count, state_file);
+        }
+        Ok((count, generation))
+    }
+
+    /*
+     * Start appending to the journal of `state_file`
+     * after the generation `generation`
+     * Call `replay` first, which also drops a broken tail.
+     * With `sync`, the entries are to be flushed to the disk
+     * with `take_unsynced`.
+     */
+    pub fn open(state_file: &str, generation: u64, sync: bool) -> Result<Journal> {
+        let mut journal = Journal {
+            path: journal_file_name(state_file),
+            file: None,
+            generation,
+            sync,
+            unsynced: false
+        };
+        journal.reopen()?;
+        Ok(journal)
+    }
+
+    // A new journal starts with the generation it follows
+    fn reopen(&mut self) -> Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let empty = file.metadata()?.len() == 0;
+        self.file = Some(file);
+        if empty {
+            let start = Entry::Start(self.generation);
+            self.append(&start)?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Append an entry
+     * Written through to the OS right away, so that it
+     * survives the process crashing. Only survives the
+     * machine crashing as well with `sync`, once synced.
+     */
+    pub fn append(&mut self, entry: &Entry) -> Result<()> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => bail!("Journal {} is not open", self.path)
+        };
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        self.unsynced = self.sync;
+        Ok(())
+    }
+
+    /*
+     * A handle to fsync what was appended since the last call
+     * None without `sync`, or if nothing was. fsync blocks,
+     * so it is up to the caller to do it off the event loop,
+     * once for a whole batch of entries.
+     */
+    pub fn take_unsynced(&mut self) -> Result<Option<File>> {
+        if !self.unsynced {
+            return Ok(None);
+        }
+        let file = match self.file {
+            Some(ref file) => file.try_clone()?,
+            None => bail!("Journal {} is not open", self.path)
+        };
+        self.unsynced = false;
+        Ok(Some(file))
+    }
+
+    /*
+     * Move the current journal aside and start a new one
+     * Call when taking a snapshot. Returns the generation
+     * to `compact` once the snapshot is on disk.
+     */
+    pub fn rotate(&mut self) -> Result<u64> {
+        self.file = None;
+        let generation = self.generation + 1;
+        let res = fs::rename(&self.path, format!("{}.{}", self.path, generation));
+        if res.is_ok() {
+            self.generation = generation;
+        }
+        // Keep journaling either way
+        self.reopen()?;
+        res?;
+        Ok(generation)
+    }
+}
+
+/*
+ * Remove every journal of `state_file`
+ * Only once everything in them is in a snapshot.
+ */
+pub fn clear(state_file: &str) -> Result<()> {
+    compact(state_file, u64::max_value())?;
+    match fs::remove_file(journal_file_name(state_file)) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => bail!("Failed to remove the journal of {}: {:?}", state_file, e),
+        _ => Ok(())
+    }
+}
+
+/*
+ * Remove the journals moved aside up to `generation`
+ * All in a snapshot already. Blocking.
+ */
+pub fn compact(state_file: &str, generation: u64) -> Result<()> {
+    for (gen, path) in rotated_journals(state_file)? {
+        if gen <= generation {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils;
+
+    #[test]
+    fn replay_stops_at_broken_line() {
+        let state_file = format!("{}/state.json", utils::test_dir("journal-broken"));
+        {
+            let mut journal = Journal::open(&state_file, 0, false).unwrap();
+            journal.append(&Entry::Put("a".into(), "1".into())).unwrap();
+            journal.append(&Entry::Put("b".into(), "2".into())).unwrap();
+            journal.append(&Entry::Remove("a".into())).unwrap();
+        }
+        // As left by a crash in the middle of an append
+        let mut file = OpenOptions::new().append(true).open(journal_file_name(&state_file)).unwrap();
+        file.write_all(b"{\"Put\":[\"c\",").unwrap();
+
+        let mut map = HashMap::new();
+        let (count, generation) = Journal::replay(&state_file, &mut map, None).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(map.get("a"), None);
+        assert_eq!(map.get("b").map(|s| s.as_str()), Some("2"));
+        assert_eq!(map.get("c"), None);
+
+        // The broken tail is gone, so what comes next is kept
+        {
+            let mut journal = Journal::open(&state_file, generation, false).unwrap();
+            journal.append(&Entry::Put("d".into(), "4".into())).unwrap();
+        }
+        let mut map = HashMap::new();
+        let (count, _) = Journal::replay(&state_file, &mut map, None).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(map.get("b").map(|s| s.as_str()), Some("2"));
+        assert_eq!(map.get("d").map(|s| s.as_str()), Some("4"));
+    }
+
+    #[test]
+    fn unsynced_entries_in_batches() {
+        let state_file = format!("{}/state.json", utils::test_dir("journal-sync"));
+        let mut journal = Journal::open(&state_file, 0, true).unwrap();
+        // Including the start of the new journal
+        assert!(journal.take_unsynced().unwrap().is_some());
+        assert!(journal.take_unsynced().unwrap().is_none());
+        journal.append(&Entry::Put("a".into(), "1".into())).unwrap();
+        journal.append(&Entry::Remove("a".into())).unwrap();
+        journal.take_unsynced().unwrap().unwrap().sync_data().unwrap();
+        assert!(journal.take_unsynced().unwrap().is_none());
+
+        let mut journal = Journal::open(&state_file, 0, false).unwrap();
+        journal.append(&Entry::Put("a".into(), "1".into())).unwrap();
+        assert!(journal.take_unsynced().unwrap().is_none());
+    }
+
+    #[test]
+    fn replay_carries_on_from_rotated() {
+        let state_file = format!("{}/state.json", utils::test_dir("journal-rotated"));
+        {
+            let mut journal = Journal::open(&state_file, 0, false).unwrap();
+            journal.append(&Entry::Put("a".into(), "1".into())).unwrap();
+            assert_eq!(journal.rotate().unwrap(), 1);
+            journal.append(&Entry::Put("a".into(), "2".into())).unwrap();
+        }
+        let mut map = HashMap::new();
+        assert_eq!(Journal::replay(&state_file, &mut map, None).unwrap(), (2, 1));
+        assert_eq!(map.get("a").map(|s| s.as_str()), Some("2"));
+
+        compact(&state_file, 1).unwrap();
+        let mut map = HashMap::new();
+        assert_eq!(Journal::replay(&state_file, &mut map, Some(1)).unwrap(), (1, 1));
+    }
+}
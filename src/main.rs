@@ -2,10 +2,14 @@ extern crate futures;
 extern crate futures_cpupool;
 extern crate hyper;
 extern crate hyper_tls;
+#[macro_use]
 extern crate percent_encoding;
+extern crate png;
 extern crate rand;
+extern crate rusqlite;
 extern crate time;
 extern crate tokio_core;
+extern crate tokio_signal;
 
 // Introduce the serde macros for use in other modules
 #[macro_use]
@@ -22,18 +26,32 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-use futures::Future;
+use futures::{Future, Stream};
 use futures_cpupool::CpuPool;
 use std::env;
 use std::panic;
 use std::rc::Rc;
+use std::time::Duration;
 use tokio_core::reactor::Core;
+#[cfg(unix)]
+use tokio_signal::unix::{Signal, SIGTERM};
+use utils::BoxFuture;
 
 #[macro_use]
 mod utils;
+mod archive;
+mod cleanup;
+mod dispatch;
+mod journal;
+mod markup;
+mod middleware;
+mod render;
 mod state;
+mod stats;
+mod storage;
 #[macro_use]
 mod telegram;
+mod updates;
 mod bot;
 
 mod errors {
@@ -43,6 +61,8 @@ mod errors {
             Hyper(::hyper::error::Error);
             SerdeJson(::serde_json::Error);
             IO(::std::io::Error);
+            PngEncoding(::png::EncodingError);
+            Sqlite(::rusqlite::Error);
         }
     }
 }
@@ -68,6 +88,16 @@ fn main() {
         }
     }));
 
+    // `migrate <config> <kind>:<path>` copies the configured state to another storage
+    if env::args().nth(1).map_or(false, |arg| arg == "migrate") {
+        let config = utils::load_config(
+            &env::args().nth(2).expect("Please supply path to the JSON configuration")
+        ).expect("Failed to decode configuration file.");
+        let to = env::args().nth(3).expect("Please supply the storage to migrate to");
+        migrate(&config, &to);
+        return;
+    }
+
     // Load the first argument as configuration
     let config = utils::load_config(
         &(env::args().nth(1)
@@ -77,10 +107,63 @@ fn main() {
 
     // Create the tokio event machine
     let mut core = Core::new().expect("WTF: Cannot create event loop.");
-    let mut tg = telegram::Telegram::new(core.handle(), &config.token);
     let pool = Rc::new(CpuPool::new(4));
+    let mut tg = telegram::Telegram::new(core.handle(), pool.clone(), &config.token);
+    if let Some(ref api_url) = config.api_url {
+        tg.set_api_url(api_url);
+    }
+    if let Some(ref text_mode) = config.text_mode {
+        tg.set_text_mode(text_mode.parse().expect("Illegal text_mode"));
+    }
+    tg.set_long_text_as_document(config.long_text_as_document.unwrap_or(false));
+    tg.set_dispatch_limits(config.max_concurrent_chats, config.max_queued_updates);
+    if let Some(secs) = config.shutdown_timeout_secs {
+        tg.set_shutdown_timeout(Duration::from_secs(secs));
+    }
 
     let work = bot::bot_main(&mut tg, config, pool.clone())
-        .and_then(|tg| tg.spin_update_loop());
+        .and_then(|tg| tg.spin_update_loop(shutdown_signal()));
     core.run(work).unwrap();
+    info!("Bye.");
+}
+
+/*
+ * Resolves on the first SIGINT or SIGTERM
+ */
+#[cfg(unix)]
+fn shutdown_signal<'a>() -> BoxFuture<'a, ()> {
+    let interrupt = tokio_signal::ctrl_c().flatten_stream().map(|_| "SIGINT");
+    let terminate = Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM");
+    Box::new(interrupt.select(terminate)
+        .into_future()
+        .map(|(signal, _)| info!("Received {}", signal.unwrap_or("nothing")))
+        .map_err(|(e, _)| e.into()))
+}
+
+/*
+ * Resolves on the first Ctrl-C
+ * The only signal there is elsewhere.
+ */
+#[cfg(not(unix))]
+fn shutdown_signal<'a>() -> BoxFuture<'a, ()> {
+    Box::new(tokio_signal::ctrl_c().flatten_stream()
+        .into_future()
+        .map(|_| info!("Received Ctrl-C"))
+        .map_err(|(e, _)| e.into()))
+}
+
+/*
+ * Copy the state from one storage to another
+ * Each given as `<kind>:<path>`, e.g. `json:state.json`
+ * or `sqlite:state.db`. Stop the bot first.
+ */
+fn migrate(config: &utils::Config, to: &str) {
+    let from = storage::from_config(config).expect("Failed to open storage");
+    let mut parts = to.splitn(2, ':');
+    let kind: storage::Kind = parts.next().unwrap().parse().expect("Illegal storage");
+    let path = parts.next().expect("Please supply storage as <kind>:<path>");
+    let backups = config.save_policy.clone().unwrap_or_default().backups();
+    let to_backend = storage::open(kind, path, backups).expect("Failed to open storage");
+    let count = storage::migrate(&*from, &config.state_file, &*to_backend).expect("Failed to migrate");
+    info!("Copied {} keys from {} to {}", count, config.state_file, to);
 }
@@ -0,0 +1,412 @@
+use serde_json;
+use std::mem;
+use std::str::FromStr;
+use telegram::{MessageEntity, User};
+use utils::Params;
+
+/*
+ * Formatted text, built one span at a time
+ * Everything put in is taken literally: it is escaped
+ * when rendered as MarkdownV2 or HTML, and never
+ * parsed at all when sent as plain text with entities.
+ *
+ * Example:
+ * Text::new().plain("Latency: ").bold("42ms")
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Text {
+    spans: Vec<Span>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Span {
+    style: Style,
+    text: String
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Style {
+    Plain,
+    Bold,
+    Italic,
+    Code,
+    // With the language, if any
+    Pre(Option<String>),
+    #[allow(dead_code)]
+    Link(String),
+    // Mention of a user by id, for users without a username
+    #[allow(dead_code)]
+    Mention(i64)
+}
+
+/*
+ * How a Text is sent to Telegram
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    MarkdownV2,
+    Html,
+    Entities
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s.to_lowercase().as_str() {
+            "markdown" | "markdownv2" => Ok(Mode::MarkdownV2),
+            "html" => Ok(Mode::Html),
+            "entities" => Ok(Mode::Entities),
+            _ => Err(format!("Unknown text mode: {}", s))
+        }
+    }
+}
+
+// Characters with a meaning anywhere in MarkdownV2 text
+const MARKDOWN_SPECIAL: &str = "\\_*[]()~`>#+-=|{}.!";
+
+impl Text {
+    pub fn new() -> Text {
+        Text { spans: vec![] }
+    }
+
+    pub fn plain(self, text: &str) -> Text {
+        self.push(Style::Plain, text)
+    }
+
+    pub fn bold(self, text: &str) -> Text {
+        self.push(Style::Bold, text)
+    }
+
+    pub fn italic(self, text: &str) -> Text {
+        self.push(Style::Italic, text)
+    }
+
+    pub fn code(self, text: &str) -> Text {
+        self.push(Style::Code, text)
+    }
+
+    // A block of preformatted text, optionally highlighted as `language`
+    pub fn pre(self, text: &str, language: Option<&str>) -> Text {
+        self.push(Style::Pre(language.map(|l| l.to_string())), text)
+    }
+
+    #[allow(dead_code)]
+    pub fn link(self, text: &str, url: &str) -> Text {
+        self.push(Style::Link(url.to_string()), text)
+    }
+
+    #[allow(dead_code)]
+    pub fn mention(self, text: &str, user_id: i64) -> Text {
+        self.push(Style::Mention(user_id), text)
+    }
+
+    pub fn newline(self) -> Text {
+        self.plain("\n")
+    }
+
+    #[allow(dead_code)]
+    pub fn append(mut self, other: Text) -> Text {
+        self.spans.extend(other.spans);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    // In UTF-16 code units, the way Telegram counts
+    pub fn len(&self) -> usize {
+        self.spans.iter().map(|span| utf16_len(&span.text)).sum()
+    }
+
+    /*
+     * Split into parts of at most `max_len` UTF-16 code units
+     * A span is only cut at a line break, or when it does not
+     * fit into a part of its own, at the last space or anywhere
+     * as a last resort. Every piece keeps the style of its span,
+     * so no formatting is broken.
+     */
+    pub fn split(&self, max_len: usize) -> Vec<Text> {
+        let mut parts = vec![];
+        let mut current = Text::new();
+        let mut current_len = 0;
+        for span in &self.spans {
+            let mut rest = span.text.as_str();
+            while !rest.is_empty() {
+                let rest_len = utf16_len(rest);
+                if current_len + rest_len <= max_len {
+                    current = current.push(span.style.clone(), rest);
+                    current_len += rest_len;
+                    break;
+                }
+                if let Some(cut) = split_point(rest, max_len - current_len, current.is_empty()) {
+                    current = current.push(span.style.clone(), &rest[..cut]);
+                    rest = &rest[cut..];
+                }
+                parts.push(mem::replace(&mut current, Text::new()));
+                current_len = 0;
+            }
+        }
+        if !current.is_empty() {
+            parts.push(current);
+        }
+        parts
+    }
+
+    fn push(mut self, style: Style, text: &str) -> Text {
+        if !text.is_empty() {
+            self.spans.push(Span { style, text: text.to_string() });
+        }
+        self
+    }
+
+    pub fn to_plain(&self) -> String {
+        self.spans.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    /*
+     * The plain text and the entities describing its formatting
+     * Offsets and lengths are in UTF-16 code units, as Telegram wants.
+     */
+    pub fn to_entities(&self) -> (String, Vec<MessageEntity>) {
+        let mut text = String::new();
+        let mut entities = vec![];
+        let mut offset = 0;
+        for span in &self.spans {
+            let length = span.text.encode_utf16().count() as i64;
+            let entity = |kind: &str| MessageEntity {
+                kind: kind.to_string(),
+                offset,
+                length,
+                url: None,
+                user: None,
+                language: None
+            };
+            match span.style {
+                Style::Plain => (),
+                Style::Bold => entities.push(entity("bold")),
+                Style::Italic => entities.push(entity("italic")),
+                Style::Code => entities.push(entity("code")),
+                Style::Pre(ref language) => entities.push(MessageEntity {
+                    language: language.clone(),
+                    ..entity("pre")
+                }),
+                Style::Link(ref url) => entities.push(MessageEntity {
+                    url: Some(url.clone()),
+                    ..entity("text_link")
+                }),
+                Style::Mention(id) => entities.push(MessageEntity {
+                    user: Some(User { id, username: None }),
+                    ..entity("text_mention")
+                })
+            }
+            text += &span.text;
+            offset += length;
+        }
+        (text, entities)
+    }
+
+    pub fn to_markdown_v2(&self) -> String {
+        self.spans.iter().map(|span| {
+            let text = escape_markdown(&span.text, MARKDOWN_SPECIAL);
+            match span.style {
+                Style::Plain => text,
+                Style::Bold => format!("*{}*", text),
+                Style::Italic => format!("_{}_", text),
+                // Only ` and \ need escaping inside code
+                Style::Code => format!("`{}`", escape_markdown(&span.text, "\\`")),
+                Style::Pre(ref language) => format!("```{}\n{}\n```",
+                    language.as_ref().map_or("", |l| l.as_str()), escape_markdown(&span.text, "\\`")),
+                // And only ) and \ inside the URL
+                Style::Link(ref url) => format!("[{}]({})", text, escape_markdown(url, "\\)")),
+                Style::Mention(id) => format!("[{}](tg://user?id={})", text, id)
+            }
+        }).collect()
+    }
+
+    pub fn to_html(&self) -> String {
+        self.spans.iter().map(|span| {
+            let text = escape_html(&span.text);
+            match span.style {
+                Style::Plain => text,
+                Style::Bold => format!("<b>{}</b>", text),
+                Style::Italic => format!("<i>{}</i>", text),
+                Style::Code => format!("<code>{}</code>", text),
+                Style::Pre(Some(ref language)) => format!("<pre><code class=\"language-{}\">{}</code></pre>",
+                    escape_html(language), text),
+                Style::Pre(None) => format!("<pre>{}</pre>", text),
+                Style::Link(ref url) => format!("<a href=\"{}\">{}</a>", escape_html(url), text),
+                Style::Mention(id) => format!("<a href=\"tg://user?id={}\">{}</a>", id, text)
+            }
+        }).collect()
+    }
+
+    /*
+     * The parameters to send this as `field` of a message
+     * i.e. `text` or `caption`, along with the parse mode
+     * or the entities.
+     */
+    pub fn to_params(&self, field: &str, mode: Mode) -> Params {
+        let mut params = Params::new();
+        match mode {
+            Mode::MarkdownV2 => {
+                params.insert(field.to_string(), self.to_markdown_v2().into());
+                params.insert(String::from("parse_mode"), "MarkdownV2".into());
+            },
+            Mode::Html => {
+                params.insert(field.to_string(), self.to_html().into());
+                params.insert(String::from("parse_mode"), "HTML".into());
+            },
+            Mode::Entities => {
+                let (text, entities) = self.to_entities();
+                let entities_field = match field {
+                    "text" => String::from("entities"),
+                    _ => format!("{}_entities", field)
+                };
+                params.insert(field.to_string(), text.into());
+                params.insert(entities_field, serde_json::to_value(entities).unwrap());
+            }
+        }
+        params
+    }
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/*
+ * Where to cut `text` so that the first piece fits into `room`
+ * Right after the last line break that fits. Unless `force`d,
+ * None if there is none. Otherwise after the last space, or
+ * at the last character that fits.
+ */
+fn split_point(text: &str, room: usize, force: bool) -> Option<usize> {
+    // The longest prefix that fits, at a character boundary
+    let mut fits = 0;
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        len += c.len_utf16();
+        if len > room {
+            break;
+        }
+        fits = i + c.len_utf8();
+    }
+    let prefix = &text[..fits];
+    if let Some(i) = prefix.rfind('\n') {
+        return Some(i + 1);
+    }
+    if !force {
+        return None;
+    }
+    if let Some(i) = prefix.rfind(' ') {
+        return Some(i + 1);
+    }
+    // Always make progress, even if not a single character fits
+    Some(if fits > 0 { fits } else { text.chars().next().map_or(0, |c| c.len_utf8()) })
+}
+
+fn escape_markdown(text: &str, special: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(c) {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_escaping() {
+        let text = Text::new().plain("1+1=2. (a_b)").bold("*x*").code("a`b\\c");
+        assert_eq!(text.to_markdown_v2(), "1\\+1\\=2\\. \\(a\\_b\\)*\\*x\\**`a\\`b\\\\c`");
+        let pre = Text::new().pre("let x = *y;", Some("rust"));
+        assert_eq!(pre.to_markdown_v2(), "```rust\nlet x = *y;\n```");
+        let links = Text::new().link("a (b)", "http://x.y/f(1)?a=1&b=\\").plain(" ").mention("@_c.", 42);
+        assert_eq!(links.to_markdown_v2(),
+            "[a \\(b\\)](http://x.y/f(1\\)?a=1&b=\\\\) [@\\_c\\.](tg://user?id=42)");
+    }
+
+    #[test]
+    fn html_escaping() {
+        let text = Text::new().plain("a < b & \"c\"").italic("<i>").pre("x > y", Some("py"));
+        assert_eq!(text.to_html(),
+            "a &lt; b &amp; &quot;c&quot;<i>&lt;i&gt;</i><pre><code class=\"language-py\">x &gt; y</code></pre>");
+        let links = Text::new().link("a & b", "http://x.y/f(1)?a=1&b=\"").plain(" ").mention("<c>", 42);
+        assert_eq!(links.to_html(),
+            "<a href=\"http://x.y/f(1)?a=1&amp;b=&quot;\">a &amp; b</a> <a href=\"tg://user?id=42\">&lt;c&gt;</a>");
+    }
+
+    #[test]
+    fn entities_count_utf16() {
+        let (text, entities) = Text::new().plain("😀 ").bold("bold").italic("é").to_entities();
+        assert_eq!(text, "😀 boldé");
+        let spans: Vec<(&str, i64, i64)> = entities.iter()
+            .map(|e| (e.kind.as_str(), e.offset, e.length))
+            .collect();
+        assert_eq!(spans, vec![("bold", 3, 4), ("italic", 7, 1)]);
+
+        let (text, entities) = Text::new().mention("😀", 42).plain(" ")
+            .append(Text::new().link("é", "http://x.y/f(1)?a=1&b=2"))
+            .to_entities();
+        assert_eq!(text, "😀 é");
+        assert_eq!((entities[0].kind.as_str(), entities[0].offset, entities[0].length), ("text_mention", 0, 2));
+        assert_eq!(entities[0].user.as_ref().map(|u| u.id), Some(42));
+        assert_eq!((entities[1].kind.as_str(), entities[1].offset, entities[1].length), ("text_link", 3, 1));
+        assert_eq!(entities[1].url.as_ref().map(|u| u.as_str()), Some("http://x.y/f(1)?a=1&b=2"));
+    }
+
+    fn plains(parts: &[Text]) -> Vec<String> {
+        parts.iter().map(|part| part.to_plain()).collect()
+    }
+
+    #[test]
+    fn split_points() {
+        // After the last line break that fits
+        assert_eq!(split_point("ab\ncd\nef", 6, false), Some(6));
+        assert_eq!(split_point("ab cd ef", 6, false), None);
+        // Then the last space, then anywhere
+        assert_eq!(split_point("ab cd ef", 6, true), Some(6));
+        assert_eq!(split_point("abcdef", 4, true), Some(4));
+        // Never in the middle of a character
+        assert_eq!(split_point("😀😀", 3, true), Some(4));
+        assert_eq!(split_point("😀", 1, true), Some(4));
+    }
+
+    #[test]
+    fn split_short_text_whole() {
+        let text = Text::new().plain("short ").bold("text");
+        let parts = text.split(100);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0], text);
+        assert!(Text::new().split(10).is_empty());
+    }
+
+    #[test]
+    fn split_keeps_styles() {
+        let text = Text::new().plain("line one\n").bold("bold words here").plain(" end");
+        let parts = text.split(12);
+        assert_eq!(plains(&parts), vec!["line one\n", "bold words ", "here end"]);
+        assert!(parts.iter().all(|part| part.len() <= 12));
+        assert_eq!(parts[2], Text::new().bold("here").plain(" end"));
+    }
+
+    #[test]
+    fn split_counts_utf16() {
+        let parts = Text::new().plain("😀😀😀").split(4);
+        assert_eq!(plains(&parts), vec!["😀😀", "😀"]);
+    }
+}
@@ -0,0 +1,340 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use telegram::{Telegram, Update};
+use time;
+
+/*
+ * Which updates a subscriber or a middleware wants
+ * Every criterion added narrows the filter down, while
+ * several values of the same criterion are alternatives.
+ *
+ * Example: messages in private chats, with any command
+ * Filter::new().kind("message").chat_type("private").any_command()
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    kinds: Vec<String>,
+    chat_ids: Vec<i64>,
+    chat_types: Vec<String>,
+    commands: Vec<String>,
+    any_command: bool,
+    user_ids: Vec<i64>,
+    usernames: Vec<String>
+}
+
+impl Filter {
+    // Matches every update
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    // As named in `allowed_updates`, e.g. "message" or "callback_query"
+    #[allow(dead_code)]
+    pub fn kind(mut self, kind: &str) -> Filter {
+        self.kinds.push(kind.to_string());
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn chat(mut self, chat_id: i64) -> Filter {
+        self.chat_ids.push(chat_id);
+        self
+    }
+
+    // "private", "group", "supergroup" or "channel"
+    #[allow(dead_code)]
+    pub fn chat_type(mut self, chat_type: &str) -> Filter {
+        self.chat_types.push(chat_type.to_string());
+        self
+    }
+
+    // Without the `/`
+    #[allow(dead_code)]
+    pub fn command(mut self, name: &str) -> Filter {
+        self.commands.push(name.to_string());
+        self
+    }
+
+    pub fn any_command(mut self) -> Filter {
+        self.any_command = true;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn from_user(mut self, user_id: i64) -> Filter {
+        self.user_ids.push(user_id);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn from_username(mut self, username: &str) -> Filter {
+        self.usernames.push(username.to_string());
+        self
+    }
+
+    // Empty if any kind will do
+    pub fn kinds(&self) -> &[String] {
+        &self.kinds
+    }
+
+    pub fn matches(&self, update: &Update) -> bool {
+        fn any_of<T: PartialEq>(wanted: &[T], value: Option<T>) -> bool {
+            wanted.is_empty() || value.map_or(false, |v| wanted.contains(&v))
+        }
+
+        let command = update.command();
+        let from = update.from();
+        any_of(&self.kinds, Some(update.kind().to_string()))
+            && any_of(&self.chat_ids, Some(update.chat_id()))
+            && any_of(&self.chat_types, update.chat_type().map(|t| t.to_string()))
+            && any_of(&self.commands, command.map(|c| c.to_string()))
+            && (!self.any_command || command.is_some())
+            && any_of(&self.user_ids, from.map(|u| u.id))
+            && any_of(&self.usernames, from.and_then(|u| u.username.clone()))
+    }
+}
+
+/*
+ * A step every update goes through before the subscribers
+ * in the order the middlewares were added.
+ * Can modify the update. Returning false stops it:
+ * neither the later middlewares nor any subscriber see it.
+ *
+ * Any `Fn(&mut Telegram, &mut Update) -> bool` will do.
+ */
+pub trait Middleware {
+    fn handle(&self, tg: &mut Telegram, update: &mut Update) -> bool;
+}
+
+impl<F> Middleware for F
+    where F: Fn(&mut Telegram, &mut Update) -> bool
+{
+    fn handle(&self, tg: &mut Telegram, update: &mut Update) -> bool {
+        self(tg, update)
+    }
+}
+
+/*
+ * Log every update
+ */
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, _tg: &mut Telegram, update: &mut Update) -> bool {
+        info!("New update received: {:?}", update);
+        true
+    }
+}
+
+/*
+ * Drop the updates of blocked users,
+ * and of chats other than the allowed ones if any
+ */
+pub struct Authorization {
+    blocked_users: Vec<String>,
+    allowed_chats: Vec<i64>
+}
+
+impl Authorization {
+    pub fn new(blocked_users: Vec<String>, allowed_chats: Vec<i64>) -> Authorization {
+        Authorization { blocked_users, allowed_chats }
+    }
+}
+
+impl Middleware for Authorization {
+    fn handle(&self, _tg: &mut Telegram, update: &mut Update) -> bool {
+        let blocked = update.from()
+            .and_then(|user| user.username.as_ref())
+            .map_or(false, |name| self.blocked_users.contains(name));
+        let allowed = self.allowed_chats.is_empty() || self.allowed_chats.contains(&update.chat_id());
+        if blocked || !allowed {
+            info!("Dropping update {} from chat {}: not authorized", update.update_id, update.chat_id());
+        }
+        !blocked && allowed
+    }
+}
+
+/*
+ * Count the updates of each kind
+ * and log the counts every `interval` updates
+ */
+pub struct Metrics {
+    interval: u64,
+    counts: RefCell<HashMap<&'static str, u64>>
+}
+
+impl Metrics {
+    pub fn new(interval: u64) -> Metrics {
+        Metrics { interval, counts: RefCell::new(HashMap::new()) }
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, _tg: &mut Telegram, update: &mut Update) -> bool {
+        let mut counts = self.counts.borrow_mut();
+        *counts.entry(update.kind()).or_insert(0) += 1;
+        let total: u64 = counts.values().sum();
+        if self.interval > 0 && total % self.interval == 0 {
+            let mut kinds: Vec<String> = counts.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+            kinds.sort();
+            info!("{} updates so far: {}", total, kinds.join(", "));
+        }
+        true
+    }
+}
+
+/*
+ * Allow each user at most `max_updates` within `window_secs`
+ * Anything beyond is dropped until the window is over.
+ */
+pub struct RateLimit {
+    max_updates: u32,
+    window_secs: i64,
+    // User id => (start of the window, updates in it)
+    windows: RefCell<HashMap<i64, (i64, u32)>>
+}
+
+impl RateLimit {
+    pub fn new(max_updates: u32, window_secs: i64) -> RateLimit {
+        RateLimit { max_updates, window_secs, windows: RefCell::new(HashMap::new()) }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, _tg: &mut Telegram, update: &mut Update) -> bool {
+        let user_id = match update.from() {
+            Some(user) => user.id,
+            None => return true
+        };
+        let now = time::get_time().sec;
+        let mut windows = self.windows.borrow_mut();
+        // Forget the windows that are over, so that this does not grow forever
+        let window_secs = self.window_secs;
+        windows.retain(|_, &mut (start, _)| now - start < window_secs);
+        let window = windows.entry(user_id).or_insert((now, 0));
+        window.1 += 1;
+        if window.1 > self.max_updates {
+            warn!("Rate limit hit by user {}, dropping update {}", user_id, update.update_id);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn message(text: &str) -> Update {
+        serde_json::from_str(&format!(r#"{{
+            "update_id": 1,
+            "message": {{ "message_id": 2, "date": 0, "chat": {{ "id": 3, "type": "private" }}, "text": "{}" }}
+        }}"#, text)).unwrap()
+    }
+
+    fn group_message(text: &str) -> Update {
+        serde_json::from_str(&format!(r#"{{
+            "update_id": 1,
+            "message": {{
+                "message_id": 2, "date": 0, "chat": {{ "id": -6, "type": "supergroup" }},
+                "from": {{ "id": 7, "username": "yuuta" }}, "text": "{}"
+            }}
+        }}"#, text)).unwrap()
+    }
+
+    fn callback_query() -> Update {
+        serde_json::from_str(r#"{
+            "update_id": 1,
+            "callback_query": { "id": "4", "from": { "id": 5 }, "data": "rikka:sticker" }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(Filter::new().matches(&message("hi")));
+        assert!(Filter::new().matches(&callback_query()));
+        assert!(Filter::new().kinds().is_empty());
+    }
+
+    #[test]
+    fn kinds_are_alternatives() {
+        let filter = Filter::new().kind("message");
+        assert!(filter.matches(&message("hi")));
+        assert!(!filter.matches(&callback_query()));
+        let filter = filter.kind("callback_query");
+        assert!(filter.matches(&callback_query()));
+        assert_eq!(filter.kinds(), &["message".to_string(), "callback_query".to_string()]);
+    }
+
+    #[test]
+    fn criteria_narrow_down() {
+        let filter = Filter::new().kind("message").kind("callback_query").any_command();
+        assert!(filter.matches(&message("/rikka@RikkaBot gif")));
+        assert!(!filter.matches(&message("rikka")));
+        assert!(!filter.matches(&callback_query()));
+    }
+
+    #[test]
+    fn chat_criterion() {
+        let filter = Filter::new().chat(-6);
+        assert!(filter.matches(&group_message("hi")));
+        assert!(!filter.matches(&message("hi")));
+        assert!(filter.chat(3).matches(&message("hi")));
+    }
+
+    #[test]
+    fn chat_type_criterion() {
+        let filter = Filter::new().chat_type("group").chat_type("supergroup");
+        assert!(filter.matches(&group_message("hi")));
+        assert!(!filter.matches(&message("hi")));
+        // Updates without a chat type never match
+        assert!(!filter.matches(&callback_query()));
+    }
+
+    #[test]
+    fn command_criterion() {
+        let filter = Filter::new().command("rikka");
+        assert!(filter.matches(&message("/rikka@RikkaBot gif")));
+        assert!(!filter.matches(&message("/stats")));
+        assert!(!filter.matches(&message("rikka")));
+        assert!(filter.command("stats").matches(&message("/stats")));
+    }
+
+    #[test]
+    fn from_user_criterion() {
+        let filter = Filter::new().from_user(7);
+        assert!(filter.matches(&group_message("hi")));
+        // No sender at all
+        assert!(!filter.matches(&message("hi")));
+        assert!(!filter.matches(&callback_query()));
+        assert!(filter.from_user(5).matches(&callback_query()));
+    }
+
+    #[test]
+    fn from_username_criterion() {
+        let filter = Filter::new().from_username("yuuta");
+        assert!(filter.matches(&group_message("hi")));
+        // The sender of the callback query has no username
+        assert!(!filter.matches(&callback_query()));
+        assert!(!Filter::new().from_username("rikka").matches(&group_message("hi")));
+    }
+
+    #[test]
+    fn all_criteria_together() {
+        let filter = Filter::new()
+            .kind("message")
+            .chat(-6)
+            .chat_type("supergroup")
+            .command("rikka")
+            .from_user(7)
+            .from_username("yuuta");
+        assert!(filter.matches(&group_message("/rikka")));
+        assert!(!filter.matches(&group_message("/stats")));
+        assert!(!filter.matches(&message("/rikka")));
+        // Another chat is an alternative, the other criteria still apply
+        let filter = filter.chat(3);
+        assert!(filter.matches(&group_message("/rikka")));
+        assert!(!filter.matches(&message("/rikka")));
+    }
+}
@@ -0,0 +1,208 @@
+use errors::*;
+use png;
+use png::HasParameters;
+use std::cmp;
+
+/*
+ * A tiny in-process chart renderer
+ * Draws on an RGB canvas and encodes the result as PNG.
+ *
+ * The only text it can draw is numbers. Titles and
+ * legends are meant to go into the photo caption.
+ */
+
+pub type Color = [u8; 3];
+
+const BACKGROUND: Color = [0xff, 0xff, 0xff];
+const FOREGROUND: Color = [0x42, 0x42, 0x42];
+const GRID: Color = [0xe0, 0xe0, 0xe0];
+const ACCENT: Color = [0x7e, 0x57, 0xc2];
+
+const CHART_WIDTH: u32 = 800;
+const CHART_HEIGHT: u32 = 400;
+const MARGIN: u32 = 48;
+const GRID_LINES: u32 = 4;
+const FONT_SCALE: u32 = 3;
+
+/*
+ * 3x5 bitmap font for the digits 0-9
+ * Every row is 3 bits, most significant bit on the left.
+ */
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111]
+];
+
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32, background: Color) -> Canvas {
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+        for _ in 0..(width * height) {
+            pixels.extend_from_slice(&background);
+        }
+        Canvas { width, height, pixels }
+    }
+
+    // Anything outside of the canvas is silently dropped
+    pub fn set_pixel(&mut self, x: i64, y: i64, color: Color) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+        let offset = ((y as u32 * self.width + x as u32) * 3) as usize;
+        self.pixels[offset..offset + 3].copy_from_slice(&color);
+    }
+
+    pub fn fill_rect(&mut self, x: i64, y: i64, w: i64, h: i64, color: Color) {
+        for py in y..(y + h) {
+            for px in x..(x + w) {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /*
+     * Bresenham's line, `thickness` pixels wide
+     */
+    pub fn draw_line(&mut self, x0: i64, y0: i64, x1: i64, y1: i64, thickness: i64, color: Color) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.fill_rect(x - thickness / 2, y - thickness / 2, thickness, thickness, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /*
+     * Draw a non-negative number with its top-left corner at (x, y)
+     * Returns the width of the drawn text.
+     */
+    pub fn draw_number(&mut self, x: i64, y: i64, num: i64, color: Color) -> i64 {
+        let scale = FONT_SCALE as i64;
+        let text = num.to_string();
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match c.to_digit(10) {
+                Some(d) => DIGITS[d as usize],
+                None => continue
+            };
+            let gx = x + i as i64 * 4 * scale;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        self.fill_rect(gx + col * scale, y + row as i64 * scale, scale, scale, color);
+                    }
+                }
+            }
+        }
+        number_width(num)
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let mut ret = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut ret, self.width, self.height);
+            encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+            encoder.write_header()?.write_image_data(&self.pixels)?;
+        }
+        Ok(ret)
+    }
+}
+
+fn number_width(num: i64) -> i64 {
+    let len = num.to_string().len() as i64;
+    (len * 4 - 1) * FONT_SCALE as i64
+}
+
+/*
+ * Draw the axes and horizontal grid of a chart
+ * labelled from 0 to `max` on the left.
+ */
+fn draw_frame(canvas: &mut Canvas, max: i64) {
+    let (left, top) = (MARGIN as i64, MARGIN as i64 / 2);
+    let (right, bottom) = ((CHART_WIDTH - MARGIN / 2) as i64, (CHART_HEIGHT - MARGIN) as i64);
+    for i in 0..(GRID_LINES + 1) as i64 {
+        let y = bottom - (bottom - top) * i / GRID_LINES as i64;
+        canvas.draw_line(left, y, right, y, 1, GRID);
+        let label = max * i / GRID_LINES as i64;
+        let width = number_width(label);
+        canvas.draw_number(left - width - 6, y - 2 * FONT_SCALE as i64, label, FOREGROUND);
+    }
+    canvas.draw_line(left, top, left, bottom, 2, FOREGROUND);
+    canvas.draw_line(left, bottom, right, bottom, 2, FOREGROUND);
+}
+
+/*
+ * Bar chart of the values, labelled 1, 2, 3... from the left
+ */
+pub fn bar_chart(values: &[i64]) -> Result<Vec<u8>> {
+    let mut canvas = Canvas::new(CHART_WIDTH, CHART_HEIGHT, BACKGROUND);
+    let max = cmp::max(1, values.iter().cloned().max().unwrap_or(0));
+    draw_frame(&mut canvas, max);
+
+    let (left, top) = (MARGIN as i64, MARGIN as i64 / 2);
+    let (right, bottom) = ((CHART_WIDTH - MARGIN / 2) as i64, (CHART_HEIGHT - MARGIN) as i64);
+    let slot = (right - left) / cmp::max(1, values.len() as i64);
+    for (i, &v) in values.iter().enumerate() {
+        let x = left + slot * i as i64;
+        let h = (bottom - top) * v / max;
+        canvas.fill_rect(x + slot / 6, bottom - h, slot * 2 / 3, h, ACCENT);
+        let label = i as i64 + 1;
+        canvas.draw_number(x + (slot - number_width(label)) / 2, bottom + 8, label, FOREGROUND);
+    }
+    canvas.to_png()
+}
+
+/*
+ * Line chart of the values, evenly spaced from left to right
+ * The x axis is labelled with how many points ago each end is,
+ * e.g. "29" and "0" for the daily values of the last 30 days.
+ */
+pub fn line_chart(values: &[i64]) -> Result<Vec<u8>> {
+    let mut canvas = Canvas::new(CHART_WIDTH, CHART_HEIGHT, BACKGROUND);
+    let max = cmp::max(1, values.iter().cloned().max().unwrap_or(0));
+    draw_frame(&mut canvas, max);
+
+    let (left, top) = (MARGIN as i64, MARGIN as i64 / 2);
+    let (right, bottom) = ((CHART_WIDTH - MARGIN / 2) as i64, (CHART_HEIGHT - MARGIN) as i64);
+    let step = (right - left) / cmp::max(1, values.len() as i64 - 1);
+    let points: Vec<(i64, i64)> = values.iter().enumerate()
+        .map(|(i, &v)| (left + step * i as i64, bottom - (bottom - top) * v / max))
+        .collect();
+    for pair in points.windows(2) {
+        canvas.draw_line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, 3, ACCENT);
+    }
+    for &(x, y) in &points {
+        canvas.fill_rect(x - 3, y - 3, 7, 7, ACCENT);
+    }
+
+    if let Some(&(last_x, _)) = points.last() {
+        let first = values.len() as i64 - 1;
+        canvas.draw_number(left, bottom + 8, first, FOREGROUND);
+        canvas.draw_number(last_x - number_width(0), bottom + 8, 0, FOREGROUND);
+    }
+    canvas.to_png()
+}
@@ -1,42 +1,179 @@
-use futures::Future;
+use futures::{future, Future};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use serde_json;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::ops::Deref;
 use std::rc::Rc;
+use std::sync::Arc;
+use storage::Backend;
+use time;
+use errors::*;
+use journal::{self, Entry, Journal};
 use utils;
 use utils::BoxFuture;
 
 const SAVE_THRESHOLD: i32 = 30;
+const DEFAULT_BACKUPS: usize = 3;
+// The journal generation a snapshot covers, see Journal
+const JOURNAL_GENERATION_KEY: &str = "journal_generation";
+
+/*
+ * When the State is written to disk
+ * Whichever comes first of `changes` changes
+ * and a change `max_dirty_secs` old.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavePolicy {
+    // Save after this many changes (default 30)
+    pub changes: Option<i32>,
+    // Save once the oldest unsaved change is this old
+    pub max_dirty_secs: Option<u64>,
+    // Save on shutdown (default true)
+    pub on_shutdown: Option<bool>,
+    // How many previous versions of the file to keep (default 3)
+    pub backups: Option<usize>,
+    // Log every change between saves, see Journal (default true)
+    // Without `journal_sync`, only survives the process crashing.
+    pub journal: Option<bool>,
+    // fsync the journal once per update, on the CpuPool
+    // with `State::sync_journal` (default false)
+    pub journal_sync: Option<bool>
+}
+
+impl SavePolicy {
+    pub fn backups(&self) -> usize {
+        self.backups.unwrap_or(DEFAULT_BACKUPS)
+    }
+}
+
+impl Default for SavePolicy {
+    fn default() -> SavePolicy {
+        SavePolicy {
+            changes: Some(SAVE_THRESHOLD),
+            max_dirty_secs: None,
+            on_shutdown: Some(true),
+            backups: Some(DEFAULT_BACKUPS),
+            journal: Some(true),
+            journal_sync: Some(false)
+        }
+    }
+}
 
 /*
  * A State to be used in a single-threaded context
  * DO NOT use this with multi-threading
+ *
+ * Clones share the same underlying map, so that
+ * futures can keep a handle to the State.
  */
+#[derive(Clone)]
 pub struct State {
     pool: Rc<CpuPool>,
+    // Where the journal goes, next to what the Backend keeps
     state_file: String,
-    state: RefCell<HashMap<String, String>>,
-    diff: RefCell<i32>
+    backend: Arc<Backend>,
+    state: Rc<RefCell<HashMap<String, String>>>,
+    diff: Rc<RefCell<i32>>,
+    // Unix time of the oldest unsaved change
+    dirty_since: Rc<Cell<Option<i64>>>,
+    // Open once loaded, if enabled
+    journal: Rc<RefCell<Option<Journal>>>,
+    saving: Rc<RefCell<Saving>>,
+    policy: SavePolicy
+}
+
+/*
+ * At most one save is written at a time
+ * Saves asked for meanwhile wait for it, then
+ * save whatever changed since.
+ */
+#[derive(Default)]
+struct Saving {
+    in_flight: bool,
+    waiters: Vec<oneshot::Sender<()>>
 }
 
 impl State {
-    pub fn new(pool: Rc<CpuPool>, state_file: String) -> State {
+    pub fn new(pool: Rc<CpuPool>, state_file: String, backend: Arc<Backend>, policy: SavePolicy) -> State {
         State {
             pool,
             state_file,
-            state: RefCell::new(HashMap::new()),
-            diff: RefCell::new(0)
+            backend,
+            state: Rc::new(RefCell::new(HashMap::new())),
+            diff: Rc::new(RefCell::new(0)),
+            dirty_since: Rc::new(Cell::new(None)),
+            journal: Rc::new(RefCell::new(None)),
+            saving: Rc::new(RefCell::new(Saving::default())),
+            policy
         }
     }
 
+    pub fn policy(&self) -> &SavePolicy {
+        &self.policy
+    }
+
     pub fn put(&self, key: &str, value: &ToString) {
-        *self.diff.borrow_mut() += 1;
+        let value = value.to_string();
+        self.mark_dirty();
+        self.journal(Entry::Put(key.to_string(), value.clone()));
+        self.state.borrow_mut().insert(key.to_string(), value);
+    }
+
+    /*
+     * Set a value without counting it as a change
+     * Neither journaled nor a reason to save: it only
+     * goes to disk along with the next snapshot.
+     */
+    pub fn put_quietly(&self, key: &str, value: &ToString) {
         self.state.borrow_mut().insert(key.to_string(), value.to_string());
     }
 
+    pub fn remove(&self, key: &str) {
+        if self.state.borrow_mut().remove(key).is_some() {
+            self.mark_dirty();
+            self.journal(Entry::Remove(key.to_string()));
+        }
+    }
+
+    fn journal(&self, entry: Entry) {
+        if let Some(ref mut journal) = *self.journal.borrow_mut() {
+            if let Err(e) = journal.append(&entry) {
+                error!("Failed to journal {:?}: {:?}", entry, e);
+            }
+        }
+    }
+
+    /*
+     * fsync what was journaled since the last call
+     * Only with `journal_sync`. On the CpuPool, since it
+     * blocks; call it once for all the changes of an update
+     * rather than after each of them.
+     */
+    pub fn sync_journal<'a>(&self) -> BoxFuture<'a, ()> {
+        let file = match *self.journal.borrow_mut() {
+            Some(ref mut journal) => journal.take_unsynced(),
+            None => Ok(None)
+        };
+        match file {
+            Ok(Some(file)) => Box::new(self.pool.spawn_fn(move || {
+                file.sync_data().chain_err(|| "Failed to sync the journal")
+            })),
+            Ok(None) => utils::return_empty(),
+            Err(e) => Box::new(future::err(e))
+        }
+    }
+
+    fn mark_dirty(&self) {
+        *self.diff.borrow_mut() += 1;
+        if self.dirty_since.get().is_none() {
+            self.dirty_since.set(Some(time::get_time().sec));
+        }
+    }
+
     pub fn get<T>(&self, key: &str) -> Option<T>
         where T: FromStr {
             self.state.borrow().get(key)
@@ -51,33 +188,117 @@ impl State {
         serde_json::to_string(self.state.borrow().deref()).unwrap()
     }
 
-    // Load state from state_file
+    // Load state from the Backend
     // Takes ownership of self. Will give it back in the future.
+    // Fails rather than starting fresh over a state that cannot be read.
     pub fn load<'a>(self) -> BoxFuture<'a, State> {
-        Box::new(utils::read_file_str_async(self.pool.deref(), self.state_file.clone())
-            .then(move |res| {
-                match res {
-                    Ok(s) => *self.state.borrow_mut() = serde_json::from_str(&s).unwrap_or_else(|_| {
-                        warn!("Failed to decode {}. Starting fresh.", self.state_file);
-                        HashMap::new()
-                    }),
-                    Err(_) => warn!("{} does not exist. Starting fresh.", self.state_file)
-                }
-                Ok(self)
-            }))
+        let state_file = self.state_file.clone();
+        let backend = self.backend.clone();
+        let use_journal = self.policy.journal.unwrap_or(true) && backend.is_persistent();
+        Box::new(self.pool.spawn_fn(move || -> Result<_> {
+            let mut map = backend.load()?;
+            let (replayed, generation) = if use_journal {
+                let snapshot_generation = map.get(JOURNAL_GENERATION_KEY)
+                    .and_then(|generation| generation.parse().ok());
+                Journal::replay(&state_file, &mut map, snapshot_generation)?
+            } else {
+                (0, 0)
+            };
+            Ok((map, replayed, generation))
+        }).and_then(move |(map, replayed, generation)| {
+            *self.state.borrow_mut() = map;
+            if use_journal {
+                let sync = self.policy.journal_sync.unwrap_or(false);
+                *self.journal.borrow_mut() = Some(Journal::open(&self.state_file, generation, sync)?);
+            }
+            // Not in the snapshot yet
+            for _ in 0..replayed {
+                self.mark_dirty();
+            }
+            Ok(self)
+        }))
     }
 
+    // Does nothing if nothing changed since the last save
+    // Waits for a save in progress, if any, then saves.
     pub fn save<'a>(&self) -> BoxFuture<'a, ()> {
+        if self.saving.borrow().in_flight {
+            let (tx, rx) = oneshot::channel();
+            self.saving.borrow_mut().waiters.push(tx);
+            let state = self.clone();
+            return Box::new(rx.then(move |_| state.save()));
+        }
+        if *self.diff.borrow() == 0 {
+            return utils::return_empty();
+        }
+        self.saving.borrow_mut().in_flight = true;
         info!("Saving state to {}", self.state_file);
-        *self.diff.borrow_mut() = 0;
-        utils::write_file_str_async(self.pool.deref(), self.state_file.clone(), self.to_json())
+        // Only clean once written. Whatever changes meanwhile
+        // is still to be saved.
+        let taken = *self.diff.borrow();
+        let taken_at = time::get_time().sec;
+        // Everything journaled so far is in this snapshot
+        let generation = match *self.journal.borrow_mut() {
+            Some(ref mut journal) => journal.rotate()
+                .map_err(|e| error!("Failed to rotate the journal: {:?}", e))
+                .ok(),
+            None => None
+        };
+        let state_file = self.state_file.clone();
+        let backend = self.backend.clone();
+        let mut map = self.state.borrow().clone();
+        if let Some(generation) = generation {
+            map.insert(JOURNAL_GENERATION_KEY.to_string(), generation.to_string());
+        }
+        let state = self.clone();
+        // Saves are one at a time, so this snapshot is the latest
+        // on disk once written, and its journals can go.
+        Box::new(self.pool.spawn_fn(move || {
+            backend.save(&map)?;
+            match generation {
+                Some(generation) => journal::compact(&state_file, generation),
+                None => Ok(())
+            }
+        }).then(move |res| {
+            if res.is_ok() {
+                state.mark_clean(taken, taken_at);
+            }
+            state.done_saving();
+            res
+        }))
+    }
+
+    // Let the saves waiting for this one go
+    fn done_saving(&self) {
+        let waiters = {
+            let mut saving = self.saving.borrow_mut();
+            saving.in_flight = false;
+            saving.waiters.drain(..).collect::<Vec<_>>()
+        };
+        for waiter in waiters {
+            let _ = waiter.send(());
+        }
     }
 
+    // `taken` changes, as of `taken_at`, are saved
+    fn mark_clean(&self, taken: i32, taken_at: i64) {
+        let mut diff = self.diff.borrow_mut();
+        *diff = cmp::max(0, *diff - taken);
+        self.dirty_since.set(if *diff == 0 { None } else { Some(taken_at) });
+    }
+
+    // Save if the SavePolicy says it is time
     pub fn save_if_needed<'a>(&self) -> BoxFuture<'a, ()> {
-        if *self.diff.borrow() >= SAVE_THRESHOLD {
+        let enough_changes = self.policy.changes
+            .map_or(false, |changes| *self.diff.borrow() >= changes);
+        let too_old = match (self.policy.max_dirty_secs, self.dirty_since.get()) {
+            (Some(max), Some(since)) => time::get_time().sec - since >= max as i64,
+            _ => false
+        };
+        if enough_changes || too_old {
             self.save()
         } else {
             utils::return_empty()
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,527 @@
+use rand;
+use rand::Rng;
+use state::State;
+use telegram::User;
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+use time;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Activity is recorded in quarter-hours of the week, in UTC,
+// so that any time zone can be applied when reading it.
+const SECS_PER_QUARTER: i64 = 15 * 60;
+const QUARTERS_PER_WEEK: i64 = 7 * 24 * 4;
+// The epoch was on a Thursday. Weeks here start on Monday.
+const EPOCH_QUARTER_OF_WEEK: i64 = 3 * 24 * 4;
+
+// The longest window the statistics can be asked for
+pub const MAX_WINDOW_DAYS: i64 = 365;
+
+// Stickers need at least this many recent uses to be trending
+const TRENDING_MIN_USES: i64 = 3;
+
+// The inline choices of a user are forgotten after this long without any
+const INLINE_CHOICE_MAX_AGE: i64 = 90 * SECS_PER_DAY;
+
+// Kinds of media recorded from Rikka
+pub const MEDIA_STICKER: &str = "sticker";
+pub const MEDIA_ANIMATION: &str = "animation";
+pub const MEDIA_PHOTO: &str = "photo";
+pub const MEDIA_VOICE: &str = "voice";
+pub const MEDIA_KINDS: [&str; 4] = [MEDIA_STICKER, MEDIA_ANIMATION, MEDIA_PHOTO, MEDIA_VOICE];
+// Messages from everyone, counted per user
+pub const USER: &str = "user";
+
+// The update_id of the last update whose media was recorded
+const LAST_RECORDED_UPDATE_KEY: &str = "last_recorded_update";
+
+/*
+ * The statistics are plain counters in State
+ *
+ *   <kind>_<file_id>, <kind>_total                  => Scope::Global
+ *   c<chat_id>_<kind>_<file_id>, c<chat_id>_<kind>_total => Scope::Chat
+ *
+ * Every use is also counted in a daily bucket, with the
+ * day (since the epoch, in UTC) after the scope prefix:
+ *
+ *   d<day>_<kind>_<file_id>, c<chat_id>_d<day>_<kind>_<file_id>
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Global,
+    Chat(i64)
+}
+
+impl Scope {
+    fn prefix(&self) -> String {
+        match *self {
+            Scope::Global => String::new(),
+            Scope::Chat(id) => format!("c{}_", id)
+        }
+    }
+
+    fn key(&self, kind: &str, name: &str) -> String {
+        format!("{}{}_{}", self.prefix(), kind, name)
+    }
+
+    fn day_key(&self, day: i64, kind: &str, name: &str) -> String {
+        format!("{}d{}_{}_{}", self.prefix(), day, kind, name)
+    }
+}
+
+/*
+ * The time window of the statistics
+ * `Days(n)` covers the last n days including today.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    All,
+    Days(i64)
+}
+
+// The day a unix timestamp belongs to
+pub fn day_of(date: i64) -> i64 {
+    date / SECS_PER_DAY
+}
+
+pub fn today() -> i64 {
+    day_of(time::get_time().sec)
+}
+
+/*
+ * Record one use of a media by Rikka at `date`
+ * in both the global and the per-chat statistics.
+ */
+pub fn record_media(state: &State, chat_id: i64, date: i64, kind: &str, file_id: &str) {
+    let day = day_of(date);
+    for scope in &[Scope::Global, Scope::Chat(chat_id)] {
+        let key = scope.key(kind, file_id);
+        let num: i64 = state.get(&key).unwrap_or(0) + 1;
+        state.put(&key, &num);
+        let total_key = scope.key(kind, "total");
+        let total: i64 = state.get(&total_key).unwrap_or(0) + 1;
+        state.put(&total_key, &total);
+        let day_key = scope.day_key(day, kind, file_id);
+        let day_num: i64 = state.get(&day_key).unwrap_or(0) + 1;
+        state.put(&day_key, &day_num);
+        debug!("Recorded use of {} {} in {:?}: {} / {}", kind, file_id, scope, num, total);
+    }
+}
+
+/*
+ * Record the media of update `update_id`, unless already done
+ * Updates are re-delivered after a crash before their offset
+ * was saved. The update_id is put along with the counters,
+ * so that both are journaled and saved together.
+ * Returns whether anything was recorded.
+ */
+pub fn record_media_once(state: &State, update_id: i64, chat_id: i64, date: i64, kind: &str, file_id: &str) -> bool {
+    if let Some(last) = state.get::<i64>(LAST_RECORDED_UPDATE_KEY) {
+        if update_id <= last {
+            return false;
+        }
+    }
+    state.put(LAST_RECORDED_UPDATE_KEY, &update_id);
+    record_media(state, chat_id, date, kind, file_id);
+    true
+}
+
+/*
+ * Count a message from anyone, for the per-user activity
+ * Users are counted like a kind of media, with their id as file_id
+ * Their names are kept in `name_<id>`.
+ */
+pub fn record_user_message(state: &State, chat_id: i64, user: &User) {
+    for scope in &[Scope::Global, Scope::Chat(chat_id)] {
+        let key = scope.key(USER, &user.id.to_string());
+        let num: i64 = state.get(&key).unwrap_or(0) + 1;
+        state.put(&key, &num);
+        let total_key = scope.key(USER, "total");
+        let total: i64 = state.get(&total_key).unwrap_or(0) + 1;
+        state.put(&total_key, &total);
+    }
+    if let Some(ref username) = user.username {
+        state.put(&format!("name_{}", user.id), username);
+    }
+}
+
+pub fn user_name(state: &State, id: &str) -> Option<String> {
+    state.get(&format!("name_{}", id))
+}
+
+/*
+ * Total number of recorded media of a kind
+ */
+pub fn total(state: &State, scope: Scope, kind: &str, window: Window) -> i64 {
+    match window {
+        Window::All => state.get(&scope.key(kind, "total")).unwrap_or(0),
+        Window::Days(_) => media_counts(state, scope, kind, window).iter().map(|&(_, v)| v).sum()
+    }
+}
+
+/*
+ * All the recorded media of a kind with their use counts
+ * Sorted by the counts, most used first.
+ */
+pub fn media_counts(state: &State, scope: Scope, kind: &str, window: Window) -> Vec<(String, i64)> {
+    match window {
+        Window::All => {
+            let prefix = scope.key(kind, "");
+            let total_key = scope.key(kind, "total");
+            sort_counts(state.keys().into_iter()
+                .filter(|k| k.starts_with(&prefix) && k != &total_key)
+                .filter_map(|k| state.get::<i64>(&k).map(|v| (k.replacen(&prefix, "", 1), v)))
+                .collect())
+        },
+        Window::Days(days) => {
+            let today = today();
+            sort_counts(range_counts(state, scope, kind, today - days + 1, today).into_iter().collect())
+        }
+    }
+}
+
+fn sort_counts(mut records: Vec<(String, i64)>) -> Vec<(String, i64)> {
+    records.sort_by(|&(ref k1, v1), &(ref k2, v2)| {
+        // Break ties by file_id to keep the order (and pagination) stable
+        match v2.cmp(&v1) {
+            Ordering::Equal => k1.cmp(k2),
+            o => o
+        }
+    });
+    records
+}
+
+/*
+ * Visit every daily bucket between two days (inclusive)
+ * with the day, the file_id and the count.
+ */
+fn for_each_day<F>(state: &State, scope: Scope, kind: &str, from: i64, to: i64, mut f: F)
+    where F: FnMut(i64, &str, i64) {
+    let prefix = format!("{}d", scope.prefix());
+    let kind_prefix = format!("{}_", kind);
+    for k in state.keys() {
+        if !k.starts_with(&prefix) {
+            continue;
+        }
+        // What follows is `<day>_<kind>_<file_id>`
+        let rest = &k[prefix.len()..];
+        let sep = match rest.find('_') {
+            Some(i) => i,
+            None => continue
+        };
+        let day = match rest[..sep].parse::<i64>() {
+            Ok(day) if day >= from && day <= to => day,
+            _ => continue
+        };
+        let rest = &rest[sep + 1..];
+        if !rest.starts_with(&kind_prefix) {
+            continue;
+        }
+        f(day, &rest[kind_prefix.len()..], state.get(&k).unwrap_or(0));
+    }
+}
+
+// The day of a daily bucket, if `key` is one
+fn bucket_day(key: &str) -> Option<i64> {
+    // Past the `c<chat_id>_` of Scope::Chat
+    let rest = match key.find('_') {
+        Some(i) if key.starts_with('c') && key[1..i].parse::<i64>().is_ok() => &key[i + 1..],
+        _ => key
+    };
+    if !rest.starts_with('d') {
+        return None;
+    }
+    let sep = rest.find('_')?;
+    rest[1..sep].parse().ok()
+}
+
+/*
+ * Sum up the daily buckets between two days (inclusive)
+ */
+fn range_counts(state: &State, scope: Scope, kind: &str, from: i64, to: i64) -> HashMap<String, i64> {
+    let mut counts = HashMap::new();
+    for_each_day(state, scope, kind, from, to, |_, id, num| {
+        *counts.entry(id.to_string()).or_insert(0) += num;
+    });
+    counts
+}
+
+/*
+ * Total uses of a kind on each of the last `days` days
+ * The oldest day comes first and today comes last.
+ */
+pub fn daily_totals(state: &State, scope: Scope, kind: &str, days: i64) -> Vec<i64> {
+    let days = cmp::max(0, cmp::min(days, MAX_WINDOW_DAYS));
+    let today = today();
+    let mut totals = vec![0; days as usize];
+    for_each_day(state, scope, kind, today - days + 1, today, |day, _, num| {
+        totals[(day - today + days - 1) as usize] += num;
+    });
+    totals
+}
+
+/*
+ * Media whose use in the last `recent` days spiked
+ * compared to the `baseline` days before that.
+ * Returns (file_id, recent uses, ratio of daily rates),
+ * the biggest spike first.
+ */
+pub fn trending(state: &State, scope: Scope, kind: &str, recent: i64, baseline: i64) -> Vec<(String, i64, f64)> {
+    let today = today();
+    let recent_counts = range_counts(state, scope, kind, today - recent + 1, today);
+    let baseline_counts = range_counts(state, scope, kind, today - recent - baseline + 1, today - recent);
+    let mut res: Vec<(String, i64, f64)> = recent_counts.into_iter()
+        .filter(|&(_, r)| r >= TRENDING_MIN_USES)
+        .map(|(id, r)| {
+            // Smooth the baseline, or anything new would be infinitely trending
+            let b = baseline_counts.get(&id).cloned().unwrap_or(0);
+            let ratio = (r as f64 / recent as f64) / ((b + 1) as f64 / baseline as f64);
+            (id, r, ratio)
+        })
+        .filter(|&(_, _, ratio)| ratio > 1.0)
+        .collect();
+    res.sort_by(|&(_, _, r1), &(_, _, r2)| r2.partial_cmp(&r1).unwrap_or(Ordering::Equal));
+    res
+}
+
+/*
+ * Record that Rikka was active at `date`
+ * Stored as `activity_<quarter of week>`
+ */
+pub fn record_activity(state: &State, date: i64) {
+    let quarter = (date.div_euclid(SECS_PER_QUARTER) + EPOCH_QUARTER_OF_WEEK).rem_euclid(QUARTERS_PER_WEEK);
+    let key = format!("activity_{}", quarter);
+    let num: i64 = state.get(&key).unwrap_or(0) + 1;
+    state.put(&key, &num);
+}
+
+/*
+ * Rikka's activity by weekday (Monday first) and hour
+ * in the time zone `offset` minutes away from UTC.
+ */
+pub fn activity_heatmap(state: &State, offset: i64) -> [[i64; 24]; 7] {
+    let mut heatmap = [[0; 24]; 7];
+    let shift = offset.div_euclid(15);
+    for quarter in 0..QUARTERS_PER_WEEK {
+        let num: i64 = state.get(&format!("activity_{}", quarter)).unwrap_or(0);
+        let hour_of_week = (quarter + shift).rem_euclid(QUARTERS_PER_WEEK) / 4;
+        heatmap[(hour_of_week / 24) as usize][(hour_of_week % 24) as usize] += num;
+    }
+    heatmap
+}
+
+/*
+ * Choose a random media of the given kind
+ * based on the rate of appearance of all the
+ * recorded media of that kind sent by Rikka.
+ * return None if error occurred.
+ */
+pub fn random_media(state: &State, kind: &str) -> Option<String> {
+    random_media_where(state, kind, |_| true)
+}
+
+/*
+ * Same as `random_media`, but only choose among
+ * the media whose file_id passes the filter.
+ */
+pub fn random_media_where<F>(state: &State, kind: &str, filter: F) -> Option<String>
+    where F: Fn(&str) -> bool {
+    let records: Vec<(String, i64)> = media_counts(state, Scope::Global, kind, Window::All).into_iter()
+        .filter(|&(ref id, _)| filter(id))
+        .collect();
+    let total: i64 = records.iter().map(|&(_, v)| v).sum();
+    if total == 0 {
+        return None;
+    }
+    let rnd_target = rand::thread_rng().gen_range(0, total);
+    let mut acc: i64 = 0;
+    for (k, v) in records {
+        acc += v;
+        if acc >= rnd_target {
+            return Some(k);
+        }
+    }
+    None
+}
+
+// The emoji Rikka attached to a sticker, if known
+pub fn sticker_emoji(state: &State, file_id: &str) -> Option<String> {
+    state.get(&format!("emoji_{}", file_id))
+}
+
+pub fn record_sticker_emoji(state: &State, file_id: &str, emoji: &str) {
+    state.put(&format!("emoji_{}", file_id), &emoji);
+}
+
+// The name of the set a sticker belongs to, if known
+pub fn sticker_set(state: &State, file_id: &str) -> Option<String> {
+    state.get(&format!("set_{}", file_id))
+}
+
+pub fn record_sticker_set(state: &State, file_id: &str, set_name: &str) {
+    state.put(&format!("set_{}", file_id), &set_name);
+}
+
+/*
+ * How many times a user picked a result in inline mode
+ * Stored as `inline_<user_id>_<result_id>`, with the time
+ * of the last pick of the user as `inline_last_<user_id>`
+ */
+pub fn inline_choices(state: &State, user_id: i64, result_id: &str) -> i64 {
+    state.get(&format!("inline_{}_{}", user_id, result_id)).unwrap_or(0)
+}
+
+pub fn record_inline_choice(state: &State, user_id: i64, result_id: &str) {
+    let key = format!("inline_{}_{}", user_id, result_id);
+    let num: i64 = state.get(&key).unwrap_or(0) + 1;
+    state.put(&key, &num);
+    state.put(&format!("inline_last_{}", user_id), &time::get_time().sec);
+}
+
+/*
+ * Forget the statistics too old to matter as of `now`
+ * That is the daily buckets outside of the longest window,
+ * and the inline choices of the users who have not
+ * picked anything for INLINE_CHOICE_MAX_AGE.
+ * Scans every key, so only run it once in a while.
+ * Returns the number of keys removed.
+ */
+pub fn prune(state: &State, now: i64) -> usize {
+    let oldest_day = day_of(now) - MAX_WINDOW_DAYS + 1;
+    let mut stale = vec![];
+    // The inline choice keys of each user
+    let mut inline: HashMap<String, Vec<String>> = HashMap::new();
+    for key in state.keys() {
+        if key.starts_with("inline_last_") {
+            continue;
+        }
+        if key.starts_with("inline_") {
+            let user = key["inline_".len()..].split('_').next().unwrap_or("").to_string();
+            inline.entry(user).or_insert_with(Vec::new).push(key);
+        } else if bucket_day(&key).map_or(false, |day| day < oldest_day) {
+            stale.push(key);
+        }
+    }
+
+    for (user, keys) in inline {
+        let last_key = format!("inline_last_{}", user);
+        match state.get::<i64>(&last_key) {
+            Some(last) if now - last > INLINE_CHOICE_MAX_AGE => {
+                stale.extend(keys);
+                stale.push(last_key);
+            },
+            Some(_) => (),
+            // Picked before the time was kept. Start counting now.
+            None => state.put(&last_key, &now)
+        }
+    }
+    for key in &stale {
+        state.remove(key);
+    }
+    stale.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_cpupool::CpuPool;
+    use state::SavePolicy;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use storage::Memory;
+
+    fn new_state() -> State {
+        State::new(Rc::new(CpuPool::new(1)), String::from("test"), Arc::new(Memory::new()), SavePolicy::default())
+    }
+
+    #[test]
+    fn prune_inline_choices() {
+        let state = new_state();
+        let now = 1000 * SECS_PER_DAY;
+        state.put("inline_1_aaaa", &3);
+        state.put("inline_last_1", &(now - INLINE_CHOICE_MAX_AGE - 1));
+        state.put("inline_2_bbbb", &1);
+        state.put("inline_last_2", &(now - 1));
+        // From before the time was kept
+        state.put("inline_3_cccc", &2);
+
+        assert_eq!(prune(&state, now), 2);
+        assert_eq!(state.get::<i64>("inline_1_aaaa"), None);
+        assert_eq!(state.get::<i64>("inline_last_1"), None);
+        assert_eq!(inline_choices(&state, 2, "bbbb"), 1);
+        assert_eq!(inline_choices(&state, 3, "cccc"), 2);
+        assert_eq!(state.get::<i64>("inline_last_3"), Some(now));
+    }
+
+    #[test]
+    fn prune_old_buckets() {
+        let state = new_state();
+        let now = 1000 * SECS_PER_DAY;
+        record_media(&state, -100, now, MEDIA_STICKER, "a");
+        record_media(&state, -100, now - (MAX_WINDOW_DAYS - 1) * SECS_PER_DAY, MEDIA_STICKER, "a");
+        record_media(&state, -100, now - MAX_WINDOW_DAYS * SECS_PER_DAY, MEDIA_STICKER, "b");
+
+        // Only the global and the chat bucket of `b`
+        assert_eq!(prune(&state, now), 2);
+        assert_eq!(state.get::<i64>(&format!("d{}_sticker_b", 1000 - MAX_WINDOW_DAYS)), None);
+        assert_eq!(state.get::<i64>(&format!("c-100_d{}_sticker_b", 1000 - MAX_WINDOW_DAYS)), None);
+        assert_eq!(state.get::<i64>(&format!("c-100_d{}_sticker_a", 1001 - MAX_WINDOW_DAYS)), Some(1));
+        // The all-time counters stay
+        assert_eq!(state.get::<i64>("sticker_b"), Some(1));
+        assert_eq!(total(&state, Scope::Chat(-100), MEDIA_STICKER, Window::All), 3);
+    }
+
+    #[test]
+    fn record_same_update_twice() {
+        let state = new_state();
+        let now = 1000 * SECS_PER_DAY;
+        assert!(record_media_once(&state, 7, -100, now, MEDIA_STICKER, "a"));
+        // Re-delivered, or older than the last one recorded
+        assert!(!record_media_once(&state, 7, -100, now, MEDIA_STICKER, "a"));
+        assert!(!record_media_once(&state, 6, -100, now, MEDIA_STICKER, "a"));
+        assert_eq!(state.get::<i64>("sticker_a"), Some(1));
+        assert_eq!(state.get::<i64>("c-100_sticker_total"), Some(1));
+        assert_eq!(state.get::<i64>("d1000_sticker_a"), Some(1));
+
+        assert!(record_media_once(&state, 8, -100, now, MEDIA_STICKER, "a"));
+        assert_eq!(state.get::<i64>("sticker_a"), Some(2));
+    }
+
+    #[test]
+    fn bucket_days() {
+        assert_eq!(bucket_day("d19000_sticker_a"), Some(19000));
+        assert_eq!(bucket_day("c-100123_d19000_sticker_a"), Some(19000));
+        assert_eq!(bucket_day("c-100123_sticker_a"), None);
+        assert_eq!(bucket_day("sticker_total"), None);
+        assert_eq!(bucket_day("dump"), None);
+    }
+
+    #[test]
+    fn trending_spikes() {
+        let state = new_state();
+        let now = time::get_time().sec;
+        let record = |days_ago: i64, id: &str, times: usize| {
+            for _ in 0..times {
+                record_media(&state, 1, now - days_ago * SECS_PER_DAY, MEDIA_STICKER, id);
+            }
+        };
+        // New and used a lot
+        record(0, "new", 5);
+        // Used as much as ever
+        record(1, "steady", 4);
+        record(10, "steady", 16);
+        // Picking up
+        record(2, "rising", 6);
+        record(20, "rising", 4);
+        // Too few uses to tell
+        record(0, "rare", 2);
+
+        let res = trending(&state, Scope::Global, MEDIA_STICKER, 7, 28);
+        let ids: Vec<&str> = res.iter().map(|&(ref id, _, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["new", "rising"]);
+        assert_eq!(res[0].1, 5);
+        assert!(res[0].2 > res[1].2);
+        assert!(trending(&state, Scope::Chat(2), MEDIA_STICKER, 7, 28).is_empty());
+    }
+}
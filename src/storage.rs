@@ -0,0 +1,314 @@
+use errors::*;
+use journal::{self, Journal};
+use rusqlite::{self, Connection};
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use utils;
+use utils::Config;
+
+/*
+ * Where a State keeps its key-value pairs between runs
+ * Both methods block, and are run on the CpuPool.
+ */
+pub trait Backend: Send + Sync {
+    // Everything stored
+    fn load(&self) -> Result<HashMap<String, String>>;
+    // Replace everything stored with `map`
+    fn save(&self, map: &HashMap<String, String>) -> Result<()>;
+    // Whether anything survives a restart, i.e. worth journaling
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+/*
+ * The kinds of Backend, as named in the `storage` config
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Json,
+    Sqlite,
+    Memory
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Kind, String> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Kind::Json),
+            "sqlite" => Ok(Kind::Sqlite),
+            "memory" => Ok(Kind::Memory),
+            _ => Err(format!("Unknown storage: {}", s))
+        }
+    }
+}
+
+/*
+ * Open the Backend of `kind` at `path`
+ * `backups` is only for the JSON file.
+ */
+pub fn open(kind: Kind, path: &str, backups: usize) -> Result<Arc<Backend>> {
+    Ok(match kind {
+        Kind::Json => Arc::new(JsonFile::new(path, backups)),
+        Kind::Sqlite => Arc::new(Sqlite::open(path)?),
+        Kind::Memory => Arc::new(Memory::new())
+    })
+}
+
+/*
+ * The Backend named by `storage` in the config, at `state_file`
+ */
+pub fn from_config(config: &Config) -> Result<Arc<Backend>> {
+    let kind = match config.storage {
+        Some(ref kind) => kind.parse()?,
+        None => Kind::Json
+    };
+    let backups = config.save_policy.clone().unwrap_or_default().backups();
+    open(kind, &config.state_file, backups)
+}
+
+/*
+ * Copy everything from one Backend to another
+ * along with what is only in the journal of `from_path` yet.
+ * Whatever was in `to` is replaced. Returns the number of keys.
+ *
+ * The journal is then folded into `from` as well, so that
+ * going back to it later does not replay it once more.
+ */
+pub fn migrate(from: &Backend, from_path: &str, to: &Backend) -> Result<usize> {
+    let mut map = from.load()?;
+    let (replayed, _) = if from.is_persistent() {
+        Journal::replay(from_path, &mut map, None)?
+    } else {
+        (0, 0)
+    };
+    to.save(&map)?;
+    if replayed > 0 {
+        from.save(&map)?;
+    }
+    if from.is_persistent() {
+        journal::clear(from_path)?;
+    }
+    Ok(map.len())
+}
+
+/*
+ * The whole map as one JSON object in a file
+ * Written atomically, keeping `backups` previous versions.
+ */
+pub struct JsonFile {
+    path: String,
+    backups: usize
+}
+
+impl JsonFile {
+    pub fn new(path: &str, backups: usize) -> JsonFile {
+        JsonFile { path: path.to_string(), backups }
+    }
+}
+
+impl Backend for JsonFile {
+    /*
+     * The newest of the file and its backups that can be decoded
+     * Nothing at all means a fresh start, but if only corrupt
+     * or unreadable ones are there, that is an error.
+     */
+    fn load(&self) -> Result<HashMap<String, String>> {
+        let mut broken = false;
+        for n in 0..(self.backups + 1) {
+            let path = if n == 0 { self.path.clone() } else { utils::backup_file_name(&self.path, n) };
+            let s = match fs::read_to_string(&path) {
+                Ok(s) => s,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    error!("Failed to read {}: {:?}", path, e);
+                    broken = true;
+                    continue;
+                }
+            };
+            match serde_json::from_str(&s) {
+                Ok(map) => {
+                    if n > 0 {
+                        warn!("Recovered state from backup {}", path);
+                    }
+                    return Ok(map);
+                },
+                Err(e) => {
+                    error!("Failed to decode {}: {:?}", path, e);
+                    broken = true;
+                }
+            }
+        }
+        if broken {
+            bail!("No usable state in {} or its backups. Refusing to start fresh over it.", self.path);
+        }
+        warn!("{} does not exist. Starting fresh.", self.path);
+        Ok(HashMap::new())
+    }
+
+    fn save(&self, map: &HashMap<String, String>) -> Result<()> {
+        utils::rotate_backups(&self.path, self.backups)?;
+        utils::write_file_str(&self.path, &serde_json::to_string(map)?)
+    }
+}
+
+/*
+ * One row per key in an SQLite database
+ * Every save is a single transaction, which only
+ * touches the rows changed since the last one.
+ */
+pub struct Sqlite {
+    inner: Mutex<SqliteInner>
+}
+
+struct SqliteInner {
+    conn: Connection,
+    // What is in the database, to tell the changes apart
+    saved: HashMap<String, String>
+}
+
+fn select_all(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = conn.prepare("SELECT key, value FROM state")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let map = rows.collect::<rusqlite::Result<HashMap<String, String>>>()?;
+    Ok(map)
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> Result<Sqlite> {
+        let conn = Connection::open(path)?;
+        conn.execute("CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY, value TEXT NOT NULL)", [])?;
+        let saved = select_all(&conn)?;
+        Ok(Sqlite { inner: Mutex::new(SqliteInner { conn, saved }) })
+    }
+}
+
+impl Backend for Sqlite {
+    fn load(&self) -> Result<HashMap<String, String>> {
+        let mut inner = self.inner.lock().unwrap();
+        let map = select_all(&inner.conn)?;
+        inner.saved = map.clone();
+        Ok(map)
+    }
+
+    fn save(&self, map: &HashMap<String, String>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        {
+            let SqliteInner { ref mut conn, ref saved } = *inner;
+            let tx = conn.transaction()?;
+            {
+                let mut upsert = tx.prepare("INSERT INTO state (key, value) VALUES (?1, ?2) \
+                    ON CONFLICT(key) DO UPDATE SET value = excluded.value")?;
+                for (key, value) in map {
+                    if saved.get(key) != Some(value) {
+                        upsert.execute(&[key, value])?;
+                    }
+                }
+                let mut delete = tx.prepare("DELETE FROM state WHERE key = ?1")?;
+                for key in saved.keys() {
+                    if !map.contains_key(key) {
+                        delete.execute(&[key])?;
+                    }
+                }
+            }
+            tx.commit()?;
+        }
+        inner.saved = map.clone();
+        Ok(())
+    }
+}
+
+/*
+ * Nothing on disk at all, e.g. for trying things out
+ * Gone with the process.
+ */
+pub struct Memory {
+    map: Mutex<HashMap<String, String>>
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory { map: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Backend for Memory {
+    fn load(&self) -> Result<HashMap<String, String>> {
+        Ok(self.map.lock().unwrap().clone())
+    }
+
+    fn save(&self, map: &HashMap<String, String>) -> Result<()> {
+        *self.map.lock().unwrap() = map.clone();
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use journal::Entry;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn json_file_falls_back_to_backup() {
+        let path = format!("{}/state.json", utils::test_dir("json-backup"));
+        let json = JsonFile::new(&path, 2);
+        assert!(json.load().unwrap().is_empty());
+        json.save(&map(&[("a", "1")])).unwrap();
+        json.save(&map(&[("a", "2")])).unwrap();
+        assert_eq!(json.load().unwrap(), map(&[("a", "2")]));
+
+        fs::write(&path, "{\"a\":").unwrap();
+        assert_eq!(json.load().unwrap(), map(&[("a", "1")]));
+
+        fs::write(utils::backup_file_name(&path, 1), "garbage").unwrap();
+        assert!(json.load().is_err());
+    }
+
+    #[test]
+    fn sqlite_round_trip() {
+        let path = format!("{}/state.db", utils::test_dir("sqlite"));
+        {
+            let db = Sqlite::open(&path).unwrap();
+            db.save(&map(&[("a", "1"), ("b", "2")])).unwrap();
+            db.save(&map(&[("a", "3"), ("c", "4")])).unwrap();
+        }
+        let db = Sqlite::open(&path).unwrap();
+        assert_eq!(db.load().unwrap(), map(&[("a", "3"), ("c", "4")]));
+        db.save(&HashMap::new()).unwrap();
+        assert!(db.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_replays_journal() {
+        let dir = utils::test_dir("migrate");
+        let from_path = format!("{}/state.json", dir);
+        let from = JsonFile::new(&from_path, 1);
+        from.save(&map(&[("a", "1"), ("b", "2")])).unwrap();
+        {
+            let mut journal = Journal::open(&from_path, 0, false).unwrap();
+            journal.append(&Entry::Put("a".into(), "3".into())).unwrap();
+            journal.append(&Entry::Remove("b".into())).unwrap();
+        }
+        let to = Sqlite::open(&format!("{}/state.db", dir)).unwrap();
+        assert_eq!(migrate(&from, &from_path, &to).unwrap(), 1);
+        assert_eq!(to.load().unwrap(), map(&[("a", "3")]));
+
+        // Folded into the source, with nothing left to replay
+        let mut left = from.load().unwrap();
+        assert_eq!(left, map(&[("a", "3")]));
+        assert_eq!(Journal::replay(&from_path, &mut left, None).unwrap().0, 0);
+    }
+}
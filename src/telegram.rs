@@ -1,19 +1,32 @@
 use errors;
 use errors::*;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::rc::Rc;
-use futures::{Future, Stream};
-use futures::future::Executor;
+use std::time::Duration;
+use futures::{future, stream, Future, Stream};
+use futures::unsync::oneshot;
+use futures_cpupool::CpuPool;
 use hyper::{Body, Chunk, Client, Method, Request, Uri};
 use hyper::client::HttpConnector;
+use hyper::header::ContentLength;
+use dispatch::Dispatcher;
 use hyper_tls::HttpsConnector;
+use markup::{Mode, Text};
+use middleware::{Filter, Middleware};
 use rand;
 use serde_json;
-use tokio_core::reactor::{Handle};
+use serde_json::Value;
+use tokio_core::reactor::{Handle, Timeout};
+use updates::UpdateStream;
 
 use utils;
-use utils::{BoxFuture, FutureChainErr};
+use utils::{BoxFuture, FutureChainErr, Params};
 
 macro_rules! assert_result {
     /*
@@ -50,53 +63,158 @@ macro_rules! assert_result {
     };
 }
 
-const REQ_TIMEOUT: u32 = 600;
+// The kinds of updates we know how to parse, see Update
+const UPDATE_KINDS: [&str; 4] = ["message", "callback_query", "inline_query", "chosen_inline_result"];
+// Updates fetched by each getUpdates
+const UPDATE_BUFFER_SIZE: usize = 100;
+const DEFAULT_MAX_CONCURRENCY: usize = 16;
+const DEFAULT_MAX_QUEUE: usize = 256;
+// How long to wait for the updates being handled on shutdown
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+const MAX_CALLBACK_DATA: usize = 64;
+// In UTF-16 code units, after parsing the formatting
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const LONG_TEXT_FILE_NAME: &str = "message.txt";
+// Bots cannot download files larger than this
+const MAX_DOWNLOAD_SIZE: u64 = 20 * 1024 * 1024;
 
-pub struct Telegram {
+/*
+ * Everything needed to call the Bot API
+ * Cheap to clone, so that a future which outlives
+ * the current update (e.g. a delayed deletion) can
+ * keep its own handle to the API.
+ */
+#[derive(Clone)]
+pub struct Api {
     tokio_handle: Handle,
+    pool: Rc<CpuPool>,
     client: Client<HttpsConnector<HttpConnector>, Body>,
+    api_url: String,
     token: String,
+    text_mode: Mode,
+    long_text_as_document: bool,
+    tasks: Rc<RefCell<Tasks>>
+}
+
+/*
+ * The futures from Api::spawn still running
+ * Shared by all the clones of an Api.
+ */
+#[derive(Default)]
+struct Tasks {
+    running: usize,
+    idle_waiters: Vec<oneshot::Sender<()>>
+}
+
+/*
+ * Which updates are done with, for confirming them
+ * on shutdown before all of them are
+ */
+#[derive(Default)]
+struct Handled {
+    // The update_ids being handled
+    pending: BTreeSet<i64>,
+    // Right after the highest update_id handled
+    completed: i64
+}
+
+impl Handled {
+    fn start(&mut self, update_id: i64) {
+        self.pending.insert(update_id);
+    }
+
+    fn finish(&mut self, update_id: i64) {
+        self.pending.remove(&update_id);
+        self.completed = cmp::max(self.completed, update_id + 1);
+    }
+
+    /*
+     * The offset confirming the updates handled so far
+     * Never past one still being handled, even if some
+     * after it are done, e.g. in other chats.
+     */
+    fn offset(&self) -> i64 {
+        match self.pending.iter().next() {
+            Some(&first) => cmp::min(self.completed, first),
+            None => self.completed
+        }
+    }
+}
+
+type Subscriber = Fn(i64, &mut Telegram, &Update) -> BoxFuture<'static, ()>;
+type ShutdownHook = Fn() -> BoxFuture<'static, ()>;
+
+/*
+ * The update loop on top of the Api
+ * All the API calls are available on Telegram
+ * itself through Deref.
+ */
+pub struct Telegram {
+    api: Api,
     last_update: i64,
-    subscribers: HashMap<i64, Rc<Fn(i64, &mut Telegram, &Update) -> BoxFuture<'static, ()>>>
+    handled: Rc<RefCell<Handled>>,
+    subscribers: BTreeMap<i64, (Filter, Rc<Subscriber>)>,
+    last_subscriber_id: i64,
+    middlewares: Vec<(Filter, Rc<Middleware>)>,
+    dispatcher: Dispatcher,
+    shutdown_hooks: Vec<Rc<ShutdownHook>>,
+    shutdown_timeout: Duration
+}
+
+impl Deref for Telegram {
+    type Target = Api;
+
+    fn deref(&self) -> &Api {
+        &self.api
+    }
+}
+
+impl DerefMut for Telegram {
+    fn deref_mut(&mut self) -> &mut Api {
+        &mut self.api
+    }
 }
 
 // The Telegram API call implementation
-#[allow(dead_code)]
-impl Telegram {
+impl Api {
     /*
-     * Initialize a Telegram instance
+     * Use another Bot API server instead of api.telegram.org
+     * e.g. a local one. Without the trailing slash.
      */
-    pub fn new(tokio_handle: Handle, token: &str) -> Telegram {
-        // Create Hyper client object before anything starts
-        let client = Client::configure()
-            .connector(HttpsConnector::new(4, &tokio_handle)
-                .expect("WTF: Cannot create HTTPS agent"))
-            .build(&tokio_handle);
+    pub fn set_api_url(&mut self, api_url: &str) {
+        self.api_url = api_url.trim_right_matches('/').to_string();
+    }
 
-        // Initialize the Telegram struct
-        Telegram {
-            tokio_handle,
-            client,
-            token: String::from(token),
-            last_update: 0,
-            subscribers: HashMap::new()
-        }
+    /*
+     * How formatted text is sent, entities by default
+     */
+    pub fn set_text_mode(&mut self, text_mode: Mode) {
+        self.text_mode = text_mode;
+    }
+
+    /*
+     * Send texts too long for one message as a document
+     * instead of splitting them
+     */
+    pub fn set_long_text_as_document(&mut self, long_text_as_document: bool) {
+        self.long_text_as_document = long_text_as_document;
     }
 
     fn uri_for_method(&self, method: &str) -> Uri {
-        format!("https://api.telegram.org/bot{}/{}", self.token, method)
+        format!("{}/bot{}/{}", self.api_url, self.token, method)
             .parse()
             .expect("Illegal URL")
     }
 
-    fn uri_for_method_with_params(&self, method: &str, params: HashMap<String, Box<ToString>>) -> Uri {
+    fn uri_for_method_with_params(&self, method: &str, params: Params) -> Uri {
         let qs = utils::build_query_string(params);
-        format!("https://api.telegram.org/bot{}/{}?{}", self.token, method, qs)
+        format!("{}/bot{}/{}?{}", self.api_url, self.token, method, qs)
             .parse()
             .expect("Illegal URL")
     }
 
-    pub fn get<'a, 'b>(&'b self, method: &str, params: HashMap<String, Box<ToString>>) -> BoxFuture<'a, Result> {
+    pub fn get<'a, 'b>(&'b self, method: &str, params: Params) -> BoxFuture<'a, Result> {
         Box::new(self.client
             .get(self.uri_for_method_with_params(method, params))
             .and_then(|res| res.body().concat2())
@@ -104,17 +222,21 @@ impl Telegram {
             .and_then(parse_body))
     }
 
-    pub fn post<'a, 'b>(&'b self, method: &str, params: HashMap<String, Box<ToString>>) -> BoxFuture<'a, Result> {
+    /*
+     * POST with a JSON body
+     * For uploading files, see `post_files`.
+     */
+    pub fn post<'a, 'b>(&'b self, method: &str, params: Params) -> BoxFuture<'a, Result> {
         Box::new(self.client
             .request({
                 let mut req: Request<Body> = Request::new(Method::Post, self.uri_for_method(method));
-                let qs = utils::build_query_string(params);
-                req.set_body(qs.clone());
+                let body = serde_json::to_vec(&params).expect("WTF: Cannot serialize parameters");
                 {
                     let headers = req.headers_mut();
-                    headers.set_raw("content-length", format!("{}", qs.len()));
-                    headers.set_raw("content-type", "application/x-www-form-urlencoded");
+                    headers.set_raw("content-length", format!("{}", body.len()));
+                    headers.set_raw("content-type", "application/json");
                 }
+                req.set_body(body);
                 req
             })
             .and_then(|res| res.body().concat2())
@@ -122,78 +244,603 @@ impl Telegram {
             .and_then(parse_body))
     }
 
-    fn next_update<'a>(&'a mut self) -> BoxFuture<'a, (&mut Telegram, Vec<Update>)> {
-        info!("Fetching update since {}", self.last_update);
-        Box::new(self.get("getUpdates", params!{
-            "timeout" => REQ_TIMEOUT,
-            "offset" => self.last_update
-        }).then(|result| {
-            // Ignore any error arising from this operation
-            // Just treat it as empty result.
-            match result {
-                Ok(resp) => Ok::<Result, Error>(resp),
-                Err(e) => {
-                    error!("Error while fetching new update: {:?}", e);
-                    Ok(Result::Nothing)
+    /*
+     * POST with a `multipart/form-data` body, for uploading files
+     * `files` are (field name, file name, content)
+     */
+    #[allow(dead_code)]
+    pub fn post_multipart<'a, 'b>(&'b self, method: &str, params: Params, files: Vec<(String, String, Vec<u8>)>) -> BoxFuture<'a, Result> {
+        request_multipart(&self.client, self.uri_for_method(method), params, files)
+    }
+
+    /*
+     * POST with files attached to some of the parameters
+     * `files` are (field name, file). Only local files and
+     * in-memory content need an upload; if there are none,
+     * this is the same as a plain `post`.
+     */
+    pub fn post_files<'a, 'b>(&'b self, method: &str, mut params: Params, files: Vec<(String, InputFile)>) -> BoxFuture<'a, Result> {
+        let mut uploads: Vec<BoxFuture<'a, (String, String, Vec<u8>)>> = vec![];
+        for (field, file) in files {
+            match file {
+                InputFile::FileId(id) | InputFile::Url(id) => {
+                    params.insert(field, Value::String(id));
+                },
+                InputFile::Memory(name, content) => {
+                    uploads.push(Box::new(future::ok((field, name, content))));
+                },
+                InputFile::Path(path) => {
+                    // Read the file off the event loop
+                    let name = Path::new(&path).file_name()
+                        .map_or_else(|| String::from("file"), |n| n.to_string_lossy().into_owned());
+                    uploads.push(Box::new(utils::read_file_async(&self.pool, path)
+                        .map(move |content| (field, name, content))));
                 }
             }
-        }).and_then(move |result| {
-            assert_result!(Result::Updates(result), result, Ok((self, vec![])));
-            if result.len() == 0 {
-                // Do nothing if result is empty
-                // This happens if no message received
-                // within timeout
-                return Ok((self, result));
+        }
+
+        if uploads.is_empty() {
+            return self.post(method, params);
+        }
+        let client = self.client.clone();
+        let uri = self.uri_for_method(method);
+        Box::new(future::join_all(uploads)
+            .and_then(move |files| request_multipart(&client, uri, params, files)))
+    }
+
+    /*
+     * Send formatted text, optionally as a reply
+     * Resolves to the sent `Result::Message`
+     *
+     * Texts too long for one message are split into parts that
+     * reply to the same message, resolving to `Result::Messages`.
+     * Or sent as a text file instead, see `set_long_text_as_document`.
+     */
+    pub fn send_message<'a, 'b>(&'b self, chat_id: i64, text: &Text, reply_to_message_id: Option<i64>, reply_markup: Option<&InlineKeyboardMarkup>) -> BoxFuture<'a, Result> {
+        let mut params = params!{
+            "chat_id" => chat_id
+        };
+        if let Some(id) = reply_to_message_id {
+            params.insert(String::from("reply_to_message_id"), Value::from(id));
+        }
+        let markup = reply_markup.map(|m| serde_json::to_value(m).unwrap());
+
+        if text.len() <= MAX_MESSAGE_LENGTH {
+            params.extend(text.to_params("text", self.text_mode));
+            if let Some(markup) = markup {
+                params.insert(String::from("reply_markup"), markup);
+            }
+            return self.post("sendMessage", params);
+        }
+        if self.long_text_as_document {
+            info!("Sending a text of {} characters as a document", text.len());
+            if let Some(markup) = markup {
+                params.insert(String::from("reply_markup"), markup);
             }
+            let document = InputFile::Memory(String::from(LONG_TEXT_FILE_NAME), text.to_plain().into_bytes());
+            return self.post_files("sendDocument", params, vec![(String::from("document"), document)]);
+        }
 
-            // Telegram API did not guarantee the order of messages
-            // Although in fact they do
-            // To be safe, just ensure the sorting here.
-            result.sort_by(|x, y| {
-                if x.update_id < y.update_id {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
+        // Send the parts one by one, so that they arrive in order.
+        // Only the last part gets the keyboard.
+        let parts = text.split(MAX_MESSAGE_LENGTH);
+        let last = parts.len() - 1;
+        info!("Sending a text of {} characters in {} parts", text.len(), parts.len());
+        let api = self.clone();
+        Box::new(stream::iter_ok(parts.into_iter().enumerate())
+            .and_then(move |(i, part)| {
+                let mut params = params.clone();
+                params.extend(part.to_params("text", api.text_mode));
+                if i == last {
+                    if let Some(ref markup) = markup {
+                        params.insert(String::from("reply_markup"), markup.clone());
+                    }
                 }
-            });
+                api.post("sendMessage", params)
+            })
+            .collect()
+            .map(|results| Result::Messages(results.into_iter()
+                .filter_map(|result| match result {
+                    Result::Message(msg) => Some(msg),
+                    _ => None
+                })
+                .collect())))
+    }
+
+    pub fn send_photo<'a, 'b>(&'b self, chat_id: i64, photo: InputFile, caption: &str) -> BoxFuture<'a, Result> {
+        self.post_files("sendPhoto", params!{
+            "chat_id" => chat_id,
+            "caption" => caption.to_string()
+        }, vec![(String::from("photo"), photo)])
+    }
+
+    #[allow(dead_code)]
+    pub fn send_document<'a, 'b>(&'b self, chat_id: i64, document: InputFile, caption: &str) -> BoxFuture<'a, Result> {
+        self.post_files("sendDocument", params!{
+            "chat_id" => chat_id,
+            "caption" => caption.to_string()
+        }, vec![(String::from("document"), document)])
+    }
+
+    pub fn send_sticker<'a, 'b>(&'b self, chat_id: i64, sticker: InputFile) -> BoxFuture<'a, Result> {
+        self.post_files("sendSticker", params!{
+            "chat_id" => chat_id
+        }, vec![(String::from("sticker"), sticker)])
+    }
+
+    /*
+     * Upload a PNG for later use in sticker sets
+     * Resolves to `Result::File`
+     */
+    #[allow(dead_code)]
+    pub fn upload_sticker_file<'a, 'b>(&'b self, user_id: i64, png_sticker: InputFile) -> BoxFuture<'a, Result> {
+        self.post_files("uploadStickerFile", params!{
+            "user_id" => user_id
+        }, vec![(String::from("png_sticker"), png_sticker)])
+    }
 
-            // Update the value of `last_update`
-            // On the next request, Telegram will
-            // mark the old messages as `read`.
-            self.last_update = result[result.len() - 1].update_id + 1;
-            return Ok((self, result));
+    /*
+     * Tell the client that a button press was handled
+     * Optionally with a notification shown to the user.
+     */
+    pub fn answer_callback_query<'a, 'b>(&'b self, callback_query_id: &str, text: Option<&str>) -> BoxFuture<'a, Result> {
+        let mut params = params!{
+            "callback_query_id" => callback_query_id
+        };
+        if let Some(text) = text {
+            params.insert(String::from("text"), Value::from(text));
+        }
+        self.post("answerCallbackQuery", params)
+    }
+
+    /*
+     * Answer an inline query with up to 50 results
+     * Pass the offset of the next page as `next_offset`,
+     * or an empty string if there are no more results.
+     */
+    pub fn answer_inline_query<'a, 'b>(&'b self, inline_query_id: &str, results: &[InlineQueryResult], next_offset: &str, is_personal: bool, cache_time: i64) -> BoxFuture<'a, Result> {
+        self.post("answerInlineQuery", params!{
+            "inline_query_id" => inline_query_id,
+            "results" => results,
+            "next_offset" => next_offset,
+            "is_personal" => is_personal,
+            "cache_time" => cache_time
+        })
+    }
+
+    /*
+     * Replace the inline keyboard under a message
+     * or remove it if `reply_markup` is None.
+     */
+    pub fn edit_message_reply_markup<'a, 'b>(&'b self, chat_id: i64, message_id: i64, reply_markup: Option<&InlineKeyboardMarkup>) -> BoxFuture<'a, Result> {
+        let mut params = params!{
+            "chat_id" => chat_id,
+            "message_id" => message_id
+        };
+        if let Some(markup) = reply_markup {
+            params.insert(String::from("reply_markup"), serde_json::to_value(markup).unwrap());
+        }
+        self.post("editMessageReplyMarkup", params)
+    }
+
+    /*
+     * Replace the text of a message sent by the bot
+     * The keyboard is removed unless passed again as `reply_markup`.
+     * Resolves to the edited `Result::Message`
+     */
+    pub fn edit_message_text<'a, 'b>(&'b self, chat_id: i64, message_id: i64, text: &Text, reply_markup: Option<&InlineKeyboardMarkup>) -> BoxFuture<'a, Result> {
+        let mut params = text.to_params("text", self.text_mode);
+        params.insert(String::from("chat_id"), Value::from(chat_id));
+        params.insert(String::from("message_id"), Value::from(message_id));
+        if let Some(markup) = reply_markup {
+            params.insert(String::from("reply_markup"), serde_json::to_value(markup).unwrap());
+        }
+        self.post("editMessageText", params)
+    }
+
+    /*
+     * Replace the caption of a photo, document, etc.
+     */
+    #[allow(dead_code)]
+    pub fn edit_message_caption<'a, 'b>(&'b self, chat_id: i64, message_id: i64, caption: &Text) -> BoxFuture<'a, Result> {
+        let mut params = caption.to_params("caption", self.text_mode);
+        params.insert(String::from("chat_id"), Value::from(chat_id));
+        params.insert(String::from("message_id"), Value::from(message_id));
+        self.post("editMessageCaption", params)
+    }
+
+    /*
+     * Delete a message
+     * Only works for messages of the last 48 hours, and
+     * for messages of others only if the bot is an admin.
+     * Resolves to `Result::Bool`
+     */
+    pub fn delete_message<'a, 'b>(&'b self, chat_id: i64, message_id: i64) -> BoxFuture<'a, Result> {
+        self.post("deleteMessage", params!{
+            "chat_id" => chat_id,
+            "message_id" => message_id
+        })
+    }
+
+    /*
+     * Go back to polling with getUpdates
+     * Optionally dropping all the updates not fetched yet.
+     */
+    pub fn delete_webhook<'a, 'b>(&'b self, drop_pending_updates: bool) -> BoxFuture<'a, Result> {
+        self.post("deleteWebhook", params!{
+            "drop_pending_updates" => drop_pending_updates
+        })
+    }
+
+    /*
+     * A future that resolves after `duration`
+     */
+    pub fn delay<'a>(&self, duration: Duration) -> BoxFuture<'a, ()> {
+        match Timeout::new(duration, &self.tokio_handle) {
+            Ok(timeout) => Box::new(timeout.map_err(|e| e.into())),
+            Err(e) => Box::new(future::err(e.into()))
+        }
+    }
+
+    /*
+     * Run a future on the event loop in the background
+     * Errors are only logged. Waited for on shutdown,
+     * see `until_tasks_done`.
+     */
+    pub fn spawn<F>(&self, f: F)
+        where F: 'static + Future<Item=(), Error=Error>
+    {
+        self.tasks.borrow_mut().running += 1;
+        let tasks = self.tasks.clone();
+        self.tokio_handle.spawn(f.then(move |res| {
+            if let Err(e) = res {
+                warn!("Error suppressed: {:?}", e);
+            }
+            let mut tasks = tasks.borrow_mut();
+            tasks.running -= 1;
+            if tasks.running == 0 {
+                for tx in tasks.idle_waiters.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /*
+     * Like `spawn`, but not waited for on shutdown
+     * For what may never end, e.g. timers and loops.
+     * Dropped when the event loop stops.
+     */
+    pub fn spawn_background<F>(&self, f: F)
+        where F: 'static + Future<Item=(), Error=Error>
+    {
+        self.tokio_handle.spawn(f.map_err(|e| {
+            warn!("Error suppressed: {:?}", e);
+            ()
+        }));
+    }
+
+    /*
+     * Resolves once every future from `spawn` is done
+     */
+    pub fn until_tasks_done<'a>(&self) -> BoxFuture<'a, ()> {
+        let mut tasks = self.tasks.borrow_mut();
+        if tasks.running == 0 {
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        tasks.idle_waiters.push(tx);
+        Box::new(rx.map_err(|_| "Api is gone".into()))
+    }
+
+    /*
+     * Get the information needed to download a file
+     * Resolves to `Result::File`
+     */
+    pub fn get_file<'a, 'b>(&'b self, file_id: &str) -> BoxFuture<'a, Result> {
+        self.post("getFile", params!{
+            "file_id" => file_id.to_string()
+        })
+    }
+
+    /*
+     * Get what a user is in a chat, e.g. an admin
+     * Resolves to `Result::ChatMember`
+     */
+    pub fn get_chat_member<'a, 'b>(&'b self, chat_id: i64, user_id: i64) -> BoxFuture<'a, Result> {
+        self.post("getChatMember", params!{
+            "chat_id" => chat_id,
+            "user_id" => user_id
+        })
+    }
+
+    /*
+     * Download the file behind a file_id to `dest`
+     * The content is streamed to disk through the CpuPool
+     * and only moved to `dest` once complete.
+     * Resolves to the number of bytes written.
+     */
+    pub fn download_file<'a, 'b>(&'b self, file_id: &str, dest: &str) -> BoxFuture<'a, u64> {
+        let client = self.client.clone();
+        let pool = self.pool.clone();
+        let file_url = format!("{}/file/bot{}", self.api_url, self.token);
+        let dest = dest.to_string();
+        Box::new(self.get_file(file_id).and_then(move |result| -> BoxFuture<'a, u64> {
+            let file = match result {
+                Result::File(file) => file,
+                x => return Box::new(future::err(format!("Unexpected response to getFile: {:?}", x).into()))
+            };
+            if file.file_size.unwrap_or(0) as u64 > MAX_DOWNLOAD_SIZE {
+                return Box::new(future::err(format!("File {} is too large to download", file.file_id).into()));
+            }
+            match file.file_path {
+                Some(path) => download_to(&client, pool, format!("{}/{}", file_url, path), dest),
+                None => Box::new(future::err(format!("File {} is not available", file.file_id).into()))
+            }
         }))
     }
+}
+
+impl Telegram {
+    /*
+     * Initialize a Telegram instance
+     */
+    pub fn new(tokio_handle: Handle, pool: Rc<CpuPool>, token: &str) -> Telegram {
+        // Create Hyper client object before anything starts
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &tokio_handle)
+                .expect("WTF: Cannot create HTTPS agent"))
+            .build(&tokio_handle);
+
+        // Initialize the Telegram struct
+        let dispatcher = Dispatcher::new(tokio_handle.clone(), DEFAULT_MAX_CONCURRENCY, DEFAULT_MAX_QUEUE);
+        Telegram {
+            api: Api {
+                tokio_handle,
+                pool,
+                client,
+                api_url: String::from(DEFAULT_API_URL),
+                token: String::from(token),
+                text_mode: Mode::Entities,
+                long_text_as_document: false,
+                tasks: Rc::new(RefCell::new(Tasks::default()))
+            },
+            last_update: 0,
+            handled: Rc::new(RefCell::new(Handled::default())),
+            subscribers: BTreeMap::new(),
+            last_subscriber_id: 0,
+            middlewares: vec![],
+            dispatcher,
+            shutdown_hooks: vec![],
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS)
+        }
+    }
+
+    /*
+     * How many chats are handled at once,
+     * and how many updates may wait for their turn
+     * None keeps the current limit.
+     */
+    pub fn set_dispatch_limits(&mut self, max_concurrency: Option<usize>, max_queue: Option<usize>) {
+        self.dispatcher.set_limits(max_concurrency.map(|n| cmp::max(1, n)), max_queue.map(|n| cmp::max(1, n)));
+    }
+
+    /*
+     * How long to wait on shutdown
+     * for the updates being handled
+     */
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
 
     /*
-     * Spin up the tail-recursive loop to fetch new messages
-     */
-    pub fn spin_update_loop<'a>(&'a mut self) -> BoxFuture<'a, ()> {
-        Box::new(self.next_update()
-            .and_then(move |(new_self, res)| {
-                if res.len() != 0 {
-                    let subscribers = new_self.get_subscribers();
-                    // Dispatch every update to every subscriber
-                    for u in res.iter() {
-                        for (id, f) in &subscribers {
-                            // Executing subscribers will return a Future
-                            let fut = f(id.clone(), new_self, u)
-                                .map_err(|e| {
-                                    warn!("Error suppressed: {:?}", e);
-                                    ()
-                                });
-                            // Add it to the event loop provided by Tokio
-                            if let Err(err) =  new_self.tokio_handle.execute(fut) {
-                                error!("Failed to schedule subscriber {}, {:?}", id, err);
-                            }
-                        }
+     * Run `f` on shutdown, once every update is done
+     * e.g. to save what is still in memory
+     */
+    pub fn on_shutdown<F>(&mut self, f: F)
+        where F: 'static + Fn() -> BoxFuture<'static, ()>
+    {
+        self.shutdown_hooks.push(Rc::new(f));
+    }
+
+    /*
+     * Start fetching from this update_id
+     * e.g. the offset saved before the last restart
+     */
+    pub fn set_update_offset(&mut self, offset: i64) {
+        self.last_update = offset;
+        self.handled.borrow_mut().completed = offset;
+    }
+
+    /*
+     * A handle to the API detached from this Telegram
+     */
+    pub fn api(&self) -> Api {
+        self.api.clone()
+    }
+
+    /*
+     * The updates since the last one handled, as a Stream
+     * Can be filtered or otherwise combined before
+     * being handed to `run_updates`.
+     */
+    pub fn updates(&self) -> UpdateStream {
+        UpdateStream::new(self.api(), self.last_update, UPDATE_BUFFER_SIZE, self.allowed_updates())
+    }
+
+    /*
+     * The kinds of updates any subscriber wants
+     * for `allowed_updates`, so that Telegram does
+     * not even send the others.
+     * Never empty, which Telegram would take as
+     * every kind there is, even those we know nothing of.
+     */
+    pub fn allowed_updates(&self) -> Vec<String> {
+        if self.subscribers.is_empty() {
+            return UPDATE_KINDS.iter().map(|k| k.to_string()).collect();
+        }
+        let mut kinds: Vec<String> = vec![];
+        for &(ref filter, _) in self.subscribers.values() {
+            let wanted = if filter.kinds().is_empty() {
+                UPDATE_KINDS.iter().map(|k| k.to_string()).collect()
+            } else {
+                filter.kinds().to_vec()
+            };
+            for kind in wanted {
+                if !kinds.contains(&kind) {
+                    kinds.push(kind);
+                }
+            }
+        }
+        kinds
+    }
+
+    /*
+     * Receive the updates at `url` instead of polling
+     * Only the kinds any subscriber wants are sent.
+     */
+    #[allow(dead_code)]
+    pub fn set_webhook<'a>(&self, url: &str) -> BoxFuture<'a, Result> {
+        self.post("setWebhook", params!{
+            "url" => url,
+            "allowed_updates" => self.allowed_updates()
+        })
+    }
+
+    /*
+     * Spin up the loop to fetch and handle new updates
+     * Once `stop` resolves, no more updates are fetched,
+     * and we shut down.
+     */
+    pub fn spin_update_loop<'a, F>(&'a mut self, stop: F) -> BoxFuture<'a, ()>
+        where F: 'a + Future<Item=(), Error=Error>
+    {
+        let updates = self.updates();
+        Box::new(self.run_updates(updates, stop).and_then(|tg| tg.shutdown()))
+    }
+
+    /*
+     * Hand every update of a Stream to the subscribers
+     * until `stop` resolves.
+     * Updates of the same chat are handled in order,
+     * see Dispatcher. Once too many are waiting, no more
+     * are taken from the Stream until some of them are done.
+     */
+    pub fn run_updates<'a, S, F>(&'a mut self, updates: S, stop: F) -> BoxFuture<'a, &'a mut Telegram>
+        where S: 'a + Stream<Item=Update, Error=Error>,
+              F: 'a + Future<Item=(), Error=Error>
+    {
+        // None once stopped, which ends the Stream
+        let updates = updates.map(Some)
+            .select(stop.into_stream().map(|_| None))
+            .take_while(|update| Ok(update.is_some()))
+            .filter_map(|update| update);
+        Box::new(updates.fold(self, |tg, update| {
+            tg.dispatch_update(update);
+            tg.dispatcher.until_room().map(move |_| tg)
+        }))
+    }
+
+    /*
+     * Wait for the updates being handled and the tasks
+     * from Api::spawn, at most `shutdown_timeout`,
+     * confirm the last update to Telegram,
+     * and then run the shutdown hooks.
+     * On timeout, only the updates handled by then are
+     * confirmed, so that the rest come again after the restart.
+     * Nothing should be dispatched anymore by then.
+     */
+    pub fn shutdown<'a>(&self) -> BoxFuture<'a, ()> {
+        info!("Shutting down. Waiting for the updates being handled.");
+        let api = self.api();
+        let last_update = self.last_update;
+        let handled = self.handled.clone();
+        let timeout = self.shutdown_timeout;
+        let hooks = self.shutdown_hooks.clone();
+        // The updates first, since they may spawn more tasks
+        let tasks_api = self.api();
+        let idle = self.dispatcher.until_idle()
+            .and_then(move |_| tasks_api.until_tasks_done());
+        Box::new(idle.map(|_| true)
+            .select(self.delay(timeout).map(|_| false))
+            .map(|(idle, _)| idle)
+            .map_err(|(e, _)| e)
+            .and_then(move |idle| {
+                let offset = if idle {
+                    last_update
+                } else {
+                    warn!("Gave up waiting for the updates being handled after {:?}", timeout);
+                    handled.borrow().offset()
+                };
+                // Otherwise Telegram sends everything since the last
+                // getUpdates again after the restart
+                info!("Confirming the updates before {}", offset);
+                api.get("getUpdates", params!{
+                    "offset" => offset,
+                    "limit" => 1,
+                    "timeout" => 0
+                }).then(|res| {
+                    if let Err(e) = res {
+                        warn!("Failed to confirm the last update: {:?}", e);
                     }
+                    Ok(())
+                })
+            })
+            .and_then(move |_| {
+                // One failing does not stop the others
+                future::join_all(hooks.iter().map(|hook| hook().then(|res| {
+                    if let Err(e) = res {
+                        error!("Shutdown hook failed: {:?}", e);
+                    }
+                    Ok(())
+                })).collect::<Vec<_>>())
+            })
+            .map(|_| info!("Shut down.")))
+    }
+
+    /*
+     * Run the update through the middlewares,
+     * then hand it to every subscriber it matches
+     */
+    fn dispatch_update(&mut self, mut update: Update) {
+        let update_id = update.update_id;
+        self.last_update = cmp::max(self.last_update, update_id + 1);
+        let middlewares = self.middlewares.clone();
+        for &(ref filter, ref middleware) in &middlewares {
+            if filter.matches(&update) && !middleware.handle(self, &mut update) {
+                self.handled.borrow_mut().finish(update_id);
+                return;
+            }
+        }
+
+        let subscribers = self.get_subscribers();
+        // Executing subscribers will return a Future
+        // All of them together count as one update.
+        let futs: Vec<_> = subscribers.iter()
+            .filter(|&(_, &(ref filter, _))| filter.matches(&update))
+            .map(|(id, &(_, ref f))| f(id.clone(), self, &update).then(|res| {
+                // One failing subscriber does not stop the others
+                if let Err(e) = res {
+                    warn!("Error suppressed: {:?}", e);
                 }
-                new_self.spin_update_loop()
+                Ok::<(), Error>(())
             }))
+            .collect();
+        if futs.is_empty() {
+            self.handled.borrow_mut().finish(update_id);
+            return;
+        }
+        self.handled.borrow_mut().start(update_id);
+        let handled = self.handled.clone();
+        let fut = future::join_all(futs).then(move |res| {
+            handled.borrow_mut().finish(update_id);
+            res.map(|_| ())
+        });
+        self.dispatcher.dispatch(update.chat_id(), Box::new(fut));
     }
 
-    fn get_subscribers(&mut self) -> HashMap<i64, Rc<Fn(i64, &mut Telegram, &Update) -> BoxFuture<'static, ()>>> {
+    fn get_subscribers(&mut self) -> BTreeMap<i64, (Filter, Rc<Subscriber>)> {
         self.subscribers.clone()
     }
 
@@ -201,24 +848,122 @@ impl Telegram {
      * Subscribe to `update` events
      * Every callback has its own id
      * which will be passed as the first argument of the closure.
+     * Ids count up from 1 in the order of subscription,
+     * which is also the order the callbacks are called in.
      * 
      * The subscribers will receive a mutable reference
      * to this Telegram object in order to unsubscribe if needed.
      */
-    pub fn subscribe<F>(&mut self, f: F)
+    pub fn subscribe<F>(&mut self, f: F) -> i64
         where F: 'static + Fn(i64, &mut Telegram, &Update) -> BoxFuture<'static, ()>
     {
-        self.subscribers.insert(rand::random::<i64>(), Rc::new(f));
+        self.subscribe_filtered(Filter::new(), f)
+    }
+
+    /*
+     * Subscribe to the updates matching a Filter only
+     */
+    pub fn subscribe_filtered<F>(&mut self, filter: Filter, f: F) -> i64
+        where F: 'static + Fn(i64, &mut Telegram, &Update) -> BoxFuture<'static, ()>
+    {
+        self.last_subscriber_id += 1;
+        self.subscribers.insert(self.last_subscriber_id, (filter, Rc::new(f)));
+        self.last_subscriber_id
+    }
+
+    /*
+     * Add a Middleware to the end of the chain
+     */
+    pub fn add_middleware<M>(&mut self, middleware: M)
+        where M: 'static + Middleware
+    {
+        self.add_middleware_filtered(Filter::new(), middleware);
+    }
+
+    /*
+     * Add a Middleware that only sees the updates matching a Filter
+     * The others pass by it untouched.
+     */
+    pub fn add_middleware_filtered<M>(&mut self, filter: Filter, middleware: M)
+        where M: 'static + Middleware
+    {
+        self.middlewares.push((filter, Rc::new(middleware)));
     }
 
     /*
      * Remove a previously subscribed callback with id
      */
+    #[allow(dead_code)]
     pub fn unsubscribe(&mut self, id: i64) {
         self.subscribers.remove(&id);
     }
 }
 
+fn request_multipart<'a>(client: &Client<HttpsConnector<HttpConnector>, Body>, uri: Uri, params: Params, files: Vec<(String, String, Vec<u8>)>) -> BoxFuture<'a, Result> {
+    Box::new(client
+        .request({
+            let mut req: Request<Body> = Request::new(Method::Post, uri);
+            let boundary = format!("rikka{:016x}", rand::random::<u64>());
+            let body = utils::build_multipart_body(&boundary, params, files);
+            {
+                let headers = req.headers_mut();
+                headers.set_raw("content-length", format!("{}", body.len()));
+                headers.set_raw("content-type", format!("multipart/form-data; boundary={}", boundary));
+            }
+            req.set_body(body);
+            req
+        })
+        .and_then(|res| res.body().concat2())
+        .chain_err(|| "POST request failed")
+        .and_then(parse_body))
+}
+
+/*
+ * Stream the content at `url` into `dest`
+ * Written to `<dest>.part` first and renamed when complete,
+ * so that `dest` never contains a partial download.
+ */
+fn download_to<'a>(client: &Client<HttpsConnector<HttpConnector>, Body>, pool: Rc<CpuPool>, url: String, dest: String) -> BoxFuture<'a, u64> {
+    let part = format!("{}.part", dest);
+    let part_cleanup = part.clone();
+    let pool_cleanup = pool.clone();
+    Box::new(client.get(url.parse().expect("Illegal URL"))
+        .map_err(Error::from)
+        .and_then(move |res| -> BoxFuture<'a, u64> {
+            if !res.status().is_success() {
+                return Box::new(future::err(format!("Download failed: {}", res.status()).into()));
+            }
+            // Don't even start if it is announced to be too large
+            if res.headers().get::<ContentLength>().map_or(false, |len| len.0 > MAX_DOWNLOAD_SIZE) {
+                return Box::new(future::err("File is too large to download".into()));
+            }
+            let body = res.body().map_err(Error::from);
+            let write_pool = pool.clone();
+            Box::new(pool.spawn_fn(move || fs::File::create(&part).map(|f| (f, part)))
+                .map_err(Error::from)
+                .and_then(move |(f, part)| body.fold((f, 0), move |(mut f, size), chunk| -> BoxFuture<'a, (fs::File, u64)> {
+                    let size = size + chunk.len() as u64;
+                    if size > MAX_DOWNLOAD_SIZE {
+                        return Box::new(future::err("File is too large to download".into()));
+                    }
+                    Box::new(write_pool.spawn_fn(move || {
+                        f.write_all(&chunk)?;
+                        Ok((f, size))
+                    }))
+                }).map(move |(f, size)| (f, size, part)))
+                .and_then(move |(f, size, part)| pool.spawn_fn(move || {
+                    f.sync_all()?;
+                    fs::rename(&part, &dest)?;
+                    Ok(size)
+                })))
+        })
+        .or_else(move |err| {
+            // Do not leave the partial file around
+            pool_cleanup.spawn_fn(move || fs::remove_file(&part_cleanup))
+                .then(move |_| Err(err))
+        }))
+}
+
 fn parse_body(body: Chunk) -> errors::Result<Result> {
     serde_json::from_slice::<Response>(&body)
         .chain_err(|| "Decode failed")
@@ -245,18 +990,115 @@ pub struct Response {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Update {
     pub update_id: i64,
-    pub message: Option<Message>
+    pub message: Option<Message>,
+    pub callback_query: Option<CallbackQuery>,
+    pub inline_query: Option<InlineQuery>,
+    pub chosen_inline_result: Option<ChosenInlineResult>
+}
+
+impl Update {
+    /*
+     * The chat this update belongs to
+     * Inline queries happen outside of chats, so they
+     * belong to the private chat with the user, whose
+     * id is the same as the user's.
+     */
+    pub fn chat_id(&self) -> i64 {
+        if let Some(ref msg) = self.message {
+            msg.chat.id
+        } else if let Some(ref query) = self.callback_query {
+            query.message.as_ref().map_or(query.from.id, |msg| msg.chat.id)
+        } else if let Some(ref query) = self.inline_query {
+            query.from.id
+        } else if let Some(ref result) = self.chosen_inline_result {
+            result.from.id
+        } else {
+            0
+        }
+    }
+
+    // The name of the field that is set, as in `allowed_updates`
+    pub fn kind(&self) -> &'static str {
+        if self.message.is_some() {
+            "message"
+        } else if self.callback_query.is_some() {
+            "callback_query"
+        } else if self.inline_query.is_some() {
+            "inline_query"
+        } else if self.chosen_inline_result.is_some() {
+            "chosen_inline_result"
+        } else {
+            "unknown"
+        }
+    }
+
+    pub fn from(&self) -> Option<&User> {
+        if let Some(ref msg) = self.message {
+            msg.from.as_ref()
+        } else if let Some(ref query) = self.callback_query {
+            Some(&query.from)
+        } else if let Some(ref query) = self.inline_query {
+            Some(&query.from)
+        } else if let Some(ref result) = self.chosen_inline_result {
+            Some(&result.from)
+        } else {
+            None
+        }
+    }
+
+    pub fn chat_type(&self) -> Option<&str> {
+        if let Some(ref msg) = self.message {
+            msg.chat.chat_type.as_ref().map(|t| t.as_str())
+        } else if let Some(ref query) = self.callback_query {
+            query.message.as_ref().and_then(|msg| msg.chat.chat_type.as_ref()).map(|t| t.as_str())
+        } else {
+            None
+        }
+    }
+
+    /*
+     * The name of the command in a message, if any
+     * Without the `/` and the `@username` of the bot.
+     */
+    pub fn command(&self) -> Option<&str> {
+        self.message.as_ref()
+            .and_then(|msg| msg.text.as_ref())
+            .filter(|text| text.starts_with("/"))
+            .and_then(|text| text[1..].split(|c: char| c == ' ' || c == '@').next())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub username: Option<String>
 }
 
+/*
+ * Formatting of a span of text, see markup::Text
+ * Offset and length are in UTF-16 code units.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageEntity {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub offset: i64,
+    pub length: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Chat {
-    pub id: i64
+    pub id: i64,
+    // "private", "group", "supergroup" or "channel"
+    #[serde(rename = "type")]
+    pub chat_type: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -266,19 +1108,247 @@ pub struct Message {
     pub chat: Chat,
     pub from: Option<User>,
     pub text: Option<String>,
-    pub sticker: Option<Sticker>
+    pub reply_to_message: Option<Box<Message>>,
+    pub sticker: Option<Sticker>,
+    pub animation: Option<Animation>,
+    pub photo: Option<Vec<PhotoSize>>,
+    pub voice: Option<Voice>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Sticker {
-    pub file_id: String
+    pub file_id: String,
+    pub file_unique_id: Option<String>,
+    pub emoji: Option<String>,
+    pub set_name: Option<String>,
+    pub is_animated: Option<bool>,
+    pub is_video: Option<bool>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Animation {
+    pub file_id: String,
+    pub file_unique_id: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PhotoSize {
+    pub file_id: String,
+    pub file_unique_id: Option<String>,
+    pub width: i64,
+    pub height: i64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Voice {
+    pub file_id: String,
+    pub file_unique_id: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct File {
+    pub file_id: String,
+    pub file_size: Option<i64>,
+    pub file_path: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMember {
+    pub user: User,
+    // "creator", "administrator", "member", "restricted", "left" or "kicked"
+    pub status: String
+}
+
+/*
+ * A file to send to Telegram
+ * Local files and in-memory content (with a file name)
+ * are uploaded; file_ids and URLs are passed as they are.
+ */
+#[derive(Debug)]
+pub enum InputFile {
+    #[allow(dead_code)]
+    Path(String),
+    Memory(String, Vec<u8>),
+    FileId(String),
+    #[allow(dead_code)]
+    Url(String)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    pub message: Option<Message>,
+    pub data: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InlineQuery {
+    pub id: String,
+    pub from: User,
+    pub query: String,
+    pub offset: String
+}
+
+// Only sent if inline feedback is enabled with @BotFather
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChosenInlineResult {
+    pub result_id: String,
+    pub from: User,
+    pub query: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InlineQueryResult {
+    #[serde(rename = "sticker")]
+    CachedSticker {
+        id: String,
+        sticker_file_id: String
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InlineKeyboardMarkup {
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>
+}
+
+/*
+ * Build inline keyboards row by row
+ *
+ *   InlineKeyboardMarkup::new()
+ *       .row(vec![InlineKeyboardButton::callback("Yes", "vote:y")])
+ *       .button(InlineKeyboardButton::callback("No", "vote:n"))
+ */
+impl InlineKeyboardMarkup {
+    pub fn new() -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup {
+            inline_keyboard: vec![]
+        }
+    }
+
+    // Append a row of buttons. Empty rows are dropped.
+    pub fn row(mut self, buttons: Vec<InlineKeyboardButton>) -> InlineKeyboardMarkup {
+        if !buttons.is_empty() {
+            self.inline_keyboard.push(buttons);
+        }
+        self
+    }
+
+    // Append a button to the last row
+    pub fn button(mut self, button: InlineKeyboardButton) -> InlineKeyboardMarkup {
+        if self.inline_keyboard.is_empty() {
+            self.inline_keyboard.push(vec![]);
+        }
+        self.inline_keyboard.last_mut().unwrap().push(button);
+        self
+    }
+
+    // Lay out the buttons in rows of at most `per_row`
+    pub fn grid(mut self, buttons: Vec<InlineKeyboardButton>, per_row: usize) -> InlineKeyboardMarkup {
+        for row in buttons.chunks(per_row) {
+            self.inline_keyboard.push(row.to_vec());
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InlineKeyboardButton {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>
+}
+
+impl InlineKeyboardButton {
+    /*
+     * A button that sends `data` back in a CallbackQuery
+     * Telegram limits the data to 64 bytes.
+     */
+    pub fn callback(text: &str, data: &str) -> InlineKeyboardButton {
+        if data.len() > MAX_CALLBACK_DATA {
+            warn!("Callback data too long, Telegram will reject it: {}", data);
+        }
+        InlineKeyboardButton {
+            text: String::from(text),
+            callback_data: Some(String::from(data)),
+            url: None
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn url(text: &str, url: &str) -> InlineKeyboardButton {
+        InlineKeyboardButton {
+            text: String::from(text),
+            callback_data: None,
+            url: Some(String::from(url))
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Result {
     Updates(Vec<Update>),
+    // Only from requests split into several messages
+    Messages(Vec<Message>),
     User(User),
     Message(Message),
+    File(File),
+    ChatMember(ChatMember),
+    Bool(bool),
     Nothing
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    fn nothing(_: i64, _: &mut Telegram, _: &Update) -> BoxFuture<'static, ()> {
+        Box::new(future::ok(()))
+    }
+
+    #[test]
+    fn handled_offset_stops_at_pending() {
+        let mut handled = Handled::default();
+        handled.completed = 10;
+        assert_eq!(handled.offset(), 10);
+        handled.start(10);
+        handled.start(11);
+        handled.start(12);
+        // Done in another chat before the first one
+        handled.finish(12);
+        assert_eq!(handled.offset(), 10);
+        handled.finish(10);
+        assert_eq!(handled.offset(), 11);
+        handled.finish(11);
+        assert_eq!(handled.offset(), 13);
+    }
+
+    #[test]
+    fn allowed_updates_of_filtered_subscribers() {
+        let core = Core::new().unwrap();
+        let mut tg = Telegram::new(core.handle(), Rc::new(CpuPool::new(1)), "token");
+        // Nothing subscribed yet is not an empty list
+        assert_eq!(tg.allowed_updates(), UPDATE_KINDS.to_vec());
+
+        tg.subscribe_filtered(Filter::new().kind("callback_query"), nothing);
+        tg.subscribe_filtered(Filter::new().kind("message").kind("callback_query").any_command(), nothing);
+        assert_eq!(tg.allowed_updates(), vec!["callback_query", "message"]);
+    }
+
+    #[test]
+    fn allowed_updates_of_unfiltered_subscribers() {
+        let core = Core::new().unwrap();
+        let mut tg = Telegram::new(core.handle(), Rc::new(CpuPool::new(1)), "token");
+        tg.subscribe_filtered(Filter::new().kind("message"), nothing);
+        let id = tg.subscribe(nothing);
+        assert_eq!(tg.allowed_updates(), vec!["message", "callback_query", "inline_query", "chosen_inline_result"]);
+
+        tg.unsubscribe(id);
+        assert_eq!(tg.allowed_updates(), vec!["message"]);
+    }
+}
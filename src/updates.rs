@@ -0,0 +1,127 @@
+use errors::*;
+use futures::{Async, Future, Poll, Stream};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use telegram::{Api, Result as TgResult, Update};
+use utils::BoxFuture;
+
+const REQ_TIMEOUT: u32 = 600;
+// The most getUpdates returns at once
+const MAX_UPDATES_PER_REQUEST: usize = 100;
+// Before trying again after a failed getUpdates
+const RETRY_DELAY_SECS: u64 = 5;
+
+/*
+ * The updates from getUpdates as a Stream
+ * Up to `capacity` updates are fetched at once.
+ *
+ * Every getUpdates confirms everything before its offset
+ * to Telegram, so the next one is only sent once all the
+ * updates of the current one have been taken out, i.e.
+ * when the consumer asks for the update after the last.
+ * Nothing that was only in the buffer is ever lost.
+ *
+ * A consumer that stops polling stops the fetching too.
+ * Never ends. Failed requests are logged and retried.
+ */
+pub struct UpdateStream {
+    api: Api,
+    // The update_id of the next update to fetch
+    // Everything before it has been taken out.
+    offset: i64,
+    capacity: usize,
+    buffer: VecDeque<Update>,
+    pending: Option<BoxFuture<'static, TgResult>>,
+    allowed_updates: Vec<String>
+}
+
+impl UpdateStream {
+    pub fn new(api: Api, offset: i64, capacity: usize, allowed_updates: Vec<String>) -> UpdateStream {
+        UpdateStream {
+            api,
+            offset,
+            capacity: cmp::max(1, capacity),
+            buffer: VecDeque::new(),
+            pending: None,
+            allowed_updates
+        }
+    }
+
+    fn fetch(&self) -> BoxFuture<'static, TgResult> {
+        let limit = cmp::min(MAX_UPDATES_PER_REQUEST, self.capacity);
+        info!("Fetching up to {} updates since {}", limit, self.offset);
+        self.api.get("getUpdates", params!{
+            "timeout" => REQ_TIMEOUT,
+            "offset" => self.offset,
+            "limit" => limit,
+            "allowed_updates" => &self.allowed_updates
+        })
+    }
+
+    fn receive(&mut self, result: Result<TgResult>) {
+        // An empty result just means nothing happened within the timeout
+        let mut updates = match result {
+            Ok(TgResult::Updates(updates)) => updates,
+            // The end of a retry delay
+            Ok(TgResult::Nothing) => return,
+            Ok(x) => {
+                warn!("Response type mismatch: expected updates, found {:?}", x);
+                return;
+            },
+            Err(e) => {
+                error!("Error while fetching new update: {:?}", e);
+                let retry = self.api.delay(Duration::from_secs(RETRY_DELAY_SECS))
+                    .map(|_| TgResult::Nothing);
+                self.pending = Some(Box::new(retry));
+                return;
+            }
+        };
+
+        // Telegram API did not guarantee the order of messages
+        // Although in fact they do
+        // To be safe, just ensure the sorting here.
+        updates.sort_by_key(|u| u.update_id);
+
+        // On the next request, Telegram will
+        // mark the old updates as `read`.
+        // Not sent before these are all taken out.
+        if let Some(last) = updates.last() {
+            self.offset = last.update_id + 1;
+        }
+        self.buffer.extend(updates);
+    }
+}
+
+impl Stream for UpdateStream {
+    type Item = Update;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Update>, Error> {
+        loop {
+            if self.pending.is_none() && self.buffer.is_empty() {
+                self.pending = Some(self.fetch());
+            }
+            let done = match self.pending {
+                Some(ref mut fut) => match fut.poll() {
+                    Ok(Async::Ready(result)) => Some(Ok(result)),
+                    Ok(Async::NotReady) => None,
+                    Err(e) => Some(Err(e))
+                },
+                None => None
+            };
+            if let Some(result) = done {
+                self.pending = None;
+                self.receive(result);
+                if self.buffer.is_empty() {
+                    // Nothing new. Ask again (or wait for the retry).
+                    continue;
+                }
+            }
+            return Ok(match self.buffer.pop_front() {
+                Some(update) => Async::Ready(Some(update)),
+                None => Async::NotReady
+            });
+        }
+    }
+}
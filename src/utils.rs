@@ -1,18 +1,55 @@
 use errors::*;
 use serde_json;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::error;
 use std::io::prelude::*;
+use std::fs;
 use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use futures::{future, Future};
 use futures_cpupool::CpuPool;
 use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use state::SavePolicy;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub token: String,
     pub rikka_name: String,
-    pub state_file: String
+    pub state_file: String,
+    pub owner_name: Option<String>,
+    // Time zone for `/when`, in minutes east of UTC
+    pub utc_offset_minutes: Option<i64>,
+    // Bot API server to use instead of api.telegram.org
+    pub api_url: Option<String>,
+    // Where to keep the media from Rikka. No archive if not set.
+    pub archive_dir: Option<String>,
+    // How formatted replies are sent: `entities` (default), `html` or `markdown`
+    pub text_mode: Option<String>,
+    // Send replies over 4096 characters as a text file instead of in parts
+    pub long_text_as_document: Option<bool>,
+    // How many chats are handled at once (default 16)
+    pub max_concurrent_chats: Option<usize>,
+    // How many updates may wait for their chat (default 256)
+    pub max_queued_updates: Option<usize>,
+    // Usernames whose updates are ignored
+    pub blocked_users: Option<Vec<String>>,
+    // If set, updates from any other chat are ignored
+    pub allowed_chats: Option<Vec<i64>>,
+    // Commands each user may send per minute. No limit if not set.
+    pub commands_per_minute: Option<u32>,
+    // Skip everything that came in while the bot was down
+    pub drop_pending_updates: Option<bool>,
+    // Ignore commands older than this, but still record the rest
+    pub max_command_age_secs: Option<i64>,
+    // How long to wait for the updates being handled on shutdown
+    pub shutdown_timeout_secs: Option<u64>,
+    // When to write the state file, see SavePolicy
+    pub save_policy: Option<SavePolicy>,
+    // Where `state_file` is kept: "json" (default), "sqlite" or "memory"
+    pub storage: Option<String>
 }
 
 /* Load configuration from file
@@ -33,19 +70,114 @@ pub fn read_file_str(file: &str) -> Result<String> {
     Ok(ret)
 }
 
-pub fn read_file_str_async<'a>(pool: &CpuPool, file: String) -> BoxFuture<'a, String> {
+/*
+ * Read the whole file as bytes
+ */
+pub fn read_file(file: &str) -> Result<Vec<u8>> {
+    let mut file = File::open(file)?;
+    let mut ret = Vec::new();
+    file.read_to_end(&mut ret)?;
+    Ok(ret)
+}
+
+pub fn read_file_async<'a>(pool: &CpuPool, file: String) -> BoxFuture<'a, Vec<u8>> {
     Box::new(pool.spawn_fn(move || {
-        read_file_str(&file)
+        read_file(&file)
     }))
 }
 
 /*
  * Write string to file
+ * Into a temporary file first, which then replaces
+ * the file, so that a crash never leaves it half-written.
  */
 pub fn write_file_str(file: &str, text: &str) -> Result<()> {
-    let mut file = File::create(file)?;
-    file.write_all(text.as_bytes())
+    let tmp_file = tmp_file_name(file);
+    let res = File::create(&tmp_file)
+        .and_then(|mut tmp| {
+            tmp.write_all(text.as_bytes())?;
+            tmp.sync_all()
+        })
         .chain_err(|| "Failed to write to file")
+        .and_then(|_| replace_file(&tmp_file, file));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_file);
+    }
+    res
+}
+
+/*
+ * A temporary file next to `file`
+ * Unique to the call, as saves may run concurrently
+ * on the CpuPool.
+ */
+fn tmp_file_name(file: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("{}.{}.{}.tmp", file, process::id(), n)
+}
+
+/*
+ * Rename `from` over `to`, and make the rename itself
+ * durable by syncing the directory
+ */
+fn replace_file(from: &str, to: &str) -> Result<()> {
+    fs::rename(from, to)
+        .chain_err(|| "Failed to replace file")?;
+    sync_parent_dir(to)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(file: &str) -> Result<()> {
+    let dir = match Path::new(file).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .chain_err(|| "Failed to sync directory")
+}
+
+// Directories cannot be opened as files elsewhere
+#[cfg(not(unix))]
+fn sync_parent_dir(_file: &str) -> Result<()> {
+    Ok(())
+}
+
+// The `n`th newest backup of `file`, from 1
+pub fn backup_file_name(file: &str, n: usize) -> String {
+    format!("{}.{}", file, n)
+}
+
+/*
+ * Keep `file` as its newest backup
+ * and shift the older ones down, keeping `count` at most
+ * The backup is a hard link, so that it is never seen
+ * half written. `file` itself must then only be replaced
+ * by renaming, as `write_file_str` does.
+ */
+pub fn rotate_backups(file: &str, count: usize) -> Result<()> {
+    if count == 0 || !Path::new(file).exists() {
+        return Ok(());
+    }
+    for n in (1..count).rev() {
+        let older = backup_file_name(file, n);
+        if Path::new(&older).exists() {
+            fs::rename(&older, backup_file_name(file, n + 1))?;
+        }
+    }
+    let newest = backup_file_name(file, 1);
+    // Only left over if `count` is 1
+    if Path::new(&newest).exists() {
+        fs::remove_file(&newest)?;
+    }
+    if fs::hard_link(file, &newest).is_err() {
+        // e.g. a file system without links
+        let tmp_file = tmp_file_name(&newest);
+        fs::copy(file, &tmp_file)?;
+        replace_file(&tmp_file, &newest)?;
+    }
+    Ok(())
 }
 
 pub fn write_file_str_async<'a>(pool: &CpuPool, file: String, text: String) -> BoxFuture<'a, ()> {
@@ -54,18 +186,70 @@ pub fn write_file_str_async<'a>(pool: &CpuPool, file: String, text: String) -> B
     }))
 }
 
+/*
+ * Parameters of an API call
+ * Serialized as a JSON object when possible,
+ * so that nested objects (e.g. `reply_markup`) just work.
+ */
+pub type Params = HashMap<String, Value>;
+
+/*
+ * DEFAULT_ENCODE_SET leaves the characters that are
+ * special in form values alone. Escape them as well,
+ * or e.g. a percentage in the text would be corrupted.
+ */
+define_encode_set! {
+    pub FORM_ENCODE_SET = [DEFAULT_ENCODE_SET] | {'%', '&', '=', '+'}
+}
+
+/*
+ * The value of a parameter in a query string or a form
+ * Strings are passed as they are, and everything else
+ * (numbers, objects, arrays) as JSON.
+ */
+pub fn param_to_string(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref v => v.to_string()
+    }
+}
+
 /*
  * Convert a HashMap to HTTP query string
+ * Values are escaped with FORM_ENCODE_SET, so that
+ * `&`, `=` and `+` in them survive.
  */
-pub fn build_query_string(params: HashMap<String, Box<ToString>>) -> String {
+pub fn build_query_string(params: Params) -> String {
     params.iter()
         .map(|(k, v)| {
-            format!("{}={}", k, utf8_percent_encode(&v.to_string(), DEFAULT_ENCODE_SET).to_string())
+            format!("{}={}", k, utf8_percent_encode(&param_to_string(v), FORM_ENCODE_SET).to_string())
         })
         .collect::<Vec<String>>()
         .join("&")
 }
 
+/*
+ * Build a `multipart/form-data` body
+ * `files` are (field name, file name, content)
+ */
+pub fn build_multipart_body(boundary: &str, params: Params, files: Vec<(String, String, Vec<u8>)>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (k, v) in params.iter() {
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            boundary, k, param_to_string(v)).as_bytes());
+    }
+    for (name, file_name, content) in files {
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, name, file_name).as_bytes());
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
 /*
  * A typed HashMap literal.
  * Automatic inference does not seem to work
@@ -115,7 +299,8 @@ macro_rules! string_hashmap {
 }
 
 /*
- * Build HTTP request params (HashMap<String, Box<ToString>>)
+ * Build HTTP request params (Params)
+ * Values can be anything serializable.
  * Usage:
  *  let options = params!{
  *      "key1" => value2,
@@ -129,14 +314,23 @@ macro_rules! params {
         ),*
     ) => {
         string_hashmap!{
-            Box<ToString>;
+            ::serde_json::Value;
             $(
-                $x => Box::new($y)
+                $x => ::serde_json::to_value($y).expect("Unserializable parameter")
             ),*
         }
     }
 }
 
+/*
+ * 64-bit FNV-1a hash
+ * Unlike the hashers in std, stable across runs and versions,
+ * so it can be used for ids that are stored.
+ */
+pub fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
 pub fn return_empty<'a>() -> BoxFuture<'a, ()> {
     Box::new(future::ok(()))
 }
@@ -162,4 +356,56 @@ impl<'a, F> FutureChainErr<'a, F::Item> for F
     {
         Box::new(self.then(|r| r.chain_err(callback)))
     }
-}
\ No newline at end of file
+}
+/*
+ * A fresh directory for a test to write files to
+ */
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    let dir = ::std::env::temp_dir().join(format!("rikka-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_string_escapes_form_characters() {
+        let query = build_query_string(params!{ "text" => "50% & a=b+c" });
+        assert_eq!(query, "text=50%25%20%26%20a%3Db%2Bc");
+    }
+
+    #[test]
+    fn params_besides_strings_are_json() {
+        assert_eq!(param_to_string(&Value::from("a b")), "a b");
+        assert_eq!(param_to_string(&Value::from(42)), "42");
+        let query = build_query_string(params!{ "allowed_updates" => vec!["message"] });
+        assert_eq!(query, "allowed_updates=[%22message%22]");
+    }
+
+    #[test]
+    fn multipart_body_has_params_then_files() {
+        let body = build_multipart_body("XyZ", params!{
+            "chat_id" => 42,
+            "caption" => "a \"b\""
+        }, vec![
+            (String::from("photo"), String::from("a.png"), vec![0x89, b'P', b'N', b'G']),
+            (String::from("thumb"), String::from("b.jpg"), b"\r\n--XyZ".to_vec())
+        ]);
+        let chat_id = b"--XyZ\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n42\r\n".to_vec();
+        let caption = b"--XyZ\r\nContent-Disposition: form-data; name=\"caption\"\r\n\r\na \"b\"\r\n".to_vec();
+        let mut files = b"--XyZ\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\nContent-Type: application/octet-stream\r\n\r\n".to_vec();
+        files.extend_from_slice(&[0x89, b'P', b'N', b'G']);
+        files.extend_from_slice(b"\r\n--XyZ\r\nContent-Disposition: form-data; name=\"thumb\"; filename=\"b.jpg\"\r\nContent-Type: application/octet-stream\r\n\r\n");
+        files.extend_from_slice(b"\r\n--XyZ\r\n--XyZ--\r\n");
+
+        // Parameters come in HashMap order, files in the given order
+        let either = [[&chat_id, &caption], [&caption, &chat_id]].iter()
+            .map(|params| [params[0].clone(), params[1].clone(), files.clone()].concat())
+            .any(|expected| body == expected);
+        assert!(either);
+    }
+}